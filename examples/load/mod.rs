@@ -0,0 +1,156 @@
+//  rtracker: bittorrent tracker
+//  Copyright (C) 2019  Justin Noah <justinnoah@gmail.com>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License.
+//
+//  This program is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//   GNU Affero General Public License for more details.
+//
+//   You should have received a copy of the GNU Affero General Public License
+//   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// The announce flood shared by the loadgen example and the throughput tests.
+//
+// Each thread is a client with its own socket (and so its own source port), keeping a window
+// of announces in flight. All of the load comes from one address, so the tracker must run with
+// [ratelimit] ip_rate = 0 and prefix_rate = 0.
+
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PROTOCOL_ID: i64 = 0x41727101980;
+// Announces in flight per thread
+const WINDOW: usize = 64;
+// Torrents the announces are spread over
+const TORRENTS: u32 = 64;
+
+fn connect_packet(transaction_id: i32) -> Vec<u8> {
+    let mut p = Vec::with_capacity(16);
+    p.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+    p.extend_from_slice(&0i32.to_be_bytes());
+    p.extend_from_slice(&transaction_id.to_be_bytes());
+    p
+}
+
+fn announce_packet(connection_id: i64, n: u32, client: u32) -> Vec<u8> {
+    let mut info_hash = [0u8; 20];
+    info_hash[..4].copy_from_slice(&(n % TORRENTS).to_be_bytes());
+    let mut peer_id = [b'-'; 20];
+    peer_id[..4].copy_from_slice(&client.to_be_bytes());
+    peer_id[4..8].copy_from_slice(&n.to_be_bytes());
+
+    let mut p = Vec::with_capacity(98);
+    p.extend_from_slice(&connection_id.to_be_bytes());
+    p.extend_from_slice(&1i32.to_be_bytes());
+    p.extend_from_slice(&(n as i32).to_be_bytes());
+    p.extend_from_slice(&info_hash);
+    p.extend_from_slice(&peer_id);
+    p.extend_from_slice(&0i64.to_be_bytes()); // downloaded
+    p.extend_from_slice(&i64::from(n % 2).to_be_bytes()); // left, half are seeders
+    p.extend_from_slice(&0i64.to_be_bytes()); // uploaded
+    p.extend_from_slice(&0i32.to_be_bytes()); // event
+    p.extend_from_slice(&0u32.to_be_bytes()); // ip
+    p.extend_from_slice(&0u32.to_be_bytes()); // key
+    p.extend_from_slice(&(-1i32).to_be_bytes()); // num_want
+    p.extend_from_slice(&(1024 + (n % 4096) as u16).to_be_bytes());
+    p
+}
+
+fn connect(sock: &UdpSocket, stop: &AtomicBool) -> Option<i64> {
+    let mut buf = [0u8; 64];
+    while !stop.load(Ordering::Relaxed) {
+        if sock.send(&connect_packet(0)).is_err() {
+            continue;
+        }
+        if let Ok(amt) = sock.recv(&mut buf) {
+            if amt >= 16 && buf[..4] == 0i32.to_be_bytes() {
+                let mut id = [0u8; 8];
+                id.copy_from_slice(&buf[8..16]);
+                return Some(i64::from_be_bytes(id));
+            }
+        }
+    }
+    None
+}
+
+fn client(target: SocketAddr, client: u32, answers: &AtomicU64, stop: &AtomicBool) {
+    let bind: SocketAddr = if target.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let sock = UdpSocket::bind(bind).expect("bind");
+    sock.connect(target).expect("connect");
+    sock.set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+
+    let mut buf = [0u8; 4096];
+    let mut n: u32 = 0;
+    'connection: while let Some(connection_id) = connect(&sock, stop) {
+        for _ in 0..WINDOW {
+            n = n.wrapping_add(1);
+            let _ = sock.send(&announce_packet(connection_id, n, client));
+        }
+        while !stop.load(Ordering::Relaxed) {
+            match sock.recv(&mut buf) {
+                Ok(amt) if amt >= 8 && buf[..4] == 1i32.to_be_bytes() => {
+                    answers.fetch_add(1, Ordering::Relaxed);
+                }
+                // An error, most likely an expired connection ID
+                Ok(_) => continue 'connection,
+                // Lost packets, fill the window back up
+                Err(_) => {
+                    for _ in 0..WINDOW {
+                        n = n.wrapping_add(1);
+                        let _ = sock.send(&announce_packet(connection_id, n, client));
+                    }
+                    continue;
+                }
+            }
+            n = n.wrapping_add(1);
+            let _ = sock.send(&announce_packet(connection_id, n, client));
+        }
+        return;
+    }
+}
+
+/// Announce to `target` from `threads` clients for `seconds`, calling `each_second` with the
+/// answers of every second. Returns the answers per second over the whole run.
+pub fn run(
+    target: SocketAddr,
+    threads: u32,
+    seconds: u64,
+    each_second: &mut dyn FnMut(u64),
+) -> f64 {
+    let answers = Arc::new(AtomicU64::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let clients: Vec<thread::JoinHandle<()>> = (0..threads)
+        .map(|i| {
+            let answers = answers.clone();
+            let stop = stop.clone();
+            thread::spawn(move || client(target, i, &answers, &stop))
+        })
+        .collect();
+
+    let start = Instant::now();
+    let mut last = 0;
+    for _ in 0..seconds {
+        thread::sleep(Duration::from_secs(1));
+        let now = answers.load(Ordering::Relaxed);
+        each_second(now - last);
+        last = now;
+    }
+    stop.store(true, Ordering::Relaxed);
+    for c in clients {
+        let _ = c.join();
+    }
+
+    answers.load(Ordering::Relaxed) as f64 / start.elapsed().as_secs_f64()
+}
@@ -0,0 +1,47 @@
+//  rtracker: bittorrent tracker
+//  Copyright (C) 2019  Justin Noah <justinnoah@gmail.com>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License.
+//
+//  This program is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//   GNU Affero General Public License for more details.
+//
+//   You should have received a copy of the GNU Affero General Public License
+//   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// UDP load generator: floods a tracker with announces and reports the answers per second.
+//
+//   cargo run --release --example loadgen -- [address] [threads] [seconds]
+//
+// Run the tracker with [ratelimit] ip_rate = 0 and prefix_rate = 0, all of the load comes
+// from one address.
+
+use std::env;
+use std::net::SocketAddr;
+use std::process;
+
+mod load;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let parsed = (
+        args.get(1).map_or("127.0.0.1:6969", |a| a.as_str()).parse::<SocketAddr>(),
+        args.get(2).map_or("4", |a| a.as_str()).parse::<u32>(),
+        args.get(3).map_or("10", |a| a.as_str()).parse::<u64>(),
+    );
+    let (target, threads, seconds) = match parsed {
+        (Ok(a), Ok(t), Ok(s)) => (a, t, s),
+        _ => {
+            eprintln!("Usage: loadgen [address] [threads] [seconds]");
+            process::exit(2);
+        }
+    };
+
+    println!("{} clients announcing to {} for {}s", threads, target, seconds);
+    let rate = load::run(target, threads, seconds, &mut |n| println!("{:>10} announces/s", n));
+    println!("{:.0} announces/s on average", rate);
+}
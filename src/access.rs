@@ -0,0 +1,365 @@
+//  rtracker: bittorrent tracker
+//  Copyright (C) 2019  Justin Noah <justinnoah@gmail.com>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License.
+//
+//  This program is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//   GNU Affero General Public License for more details.
+//
+//   You should have received a copy of the GNU Affero General Public License
+//   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, RwLock};
+use std::time::SystemTime;
+
+use config::AccessMode;
+use database::{db_access_add, db_access_list, db_access_remove, DbPool};
+use storage::{InfoHash, StoreError};
+
+/// Where the hashes of a whitelist or blacklist come from
+pub enum ListSource {
+    /// One hex encoded info hash per line, `#` starts a comment
+    File(PathBuf),
+    /// The `access_list` table of the SQLite database
+    Table(DbPool),
+}
+
+#[derive(Debug)]
+pub enum AccessError {
+    Io(PathBuf, io::Error),
+    Store(StoreError),
+    /// An edit to a list that has no file or table to keep it in
+    NoList,
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AccessError::Io(ref p, ref e) => write!(f, "{}: {}", p.display(), e),
+            AccessError::Store(ref e) => write!(f, "access_list table: {}", e),
+            AccessError::NoList => write!(f, "there is no access list file or table to edit"),
+        }
+    }
+}
+
+impl From<StoreError> for AccessError {
+    fn from(e: StoreError) -> AccessError {
+        AccessError::Store(e)
+    }
+}
+
+/// Which torrents the tracker will track
+pub struct AccessList {
+    mode:     AccessMode,
+    source:   Option<ListSource>,
+    hashes:   RwLock<HashSet<InfoHash>>,
+    // Whitelisted by the .torrent files of the torrent directory
+    torrents: RwLock<HashSet<InfoHash>>,
+    // When the file was last read, an unchanged file is not read again
+    modified: Mutex<Option<SystemTime>>,
+}
+
+// Held while a list is read or rewritten, so an edit never works from a stale read. Shared by
+// every AccessList, a config reload makes a new one for the same file while the old one may
+// still be serving an admin request.
+static EDITS: Mutex<()> = Mutex::new(());
+
+fn lock_edits() -> MutexGuard<'static, ()> {
+    EDITS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Parse a 40 character hex info hash
+pub fn parse_hex_hash(hex: &str) -> Option<InfoHash> {
+    let hex = hex.as_bytes();
+    if hex.len() != 40 {
+        return None;
+    }
+    let mut hash = [0u8; 20];
+    for (i, pair) in hex.chunks(2).enumerate() {
+        let pair = ::std::str::from_utf8(pair).ok()?;
+        hash[i] = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// The 40 character lower case hex of an info hash
+pub fn to_hex(hash: &InfoHash) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl AccessList {
+    /// Every torrent is allowed
+    pub fn open() -> AccessList {
+        AccessList {
+            mode: AccessMode::Open,
+            source: None,
+            hashes: RwLock::new(HashSet::new()),
+            torrents: RwLock::new(HashSet::new()),
+            modified: Mutex::new(None),
+        }
+    }
+
+    /// A whitelist or blacklist, empty until the first `reload`.
+    /// A whitelist may also be made of nothing but a torrent directory.
+    pub fn new(mode: AccessMode, source: Option<ListSource>) -> AccessList {
+        AccessList {
+            mode,
+            source,
+            hashes: RwLock::new(HashSet::new()),
+            torrents: RwLock::new(HashSet::new()),
+            modified: Mutex::new(None),
+        }
+    }
+
+    pub fn mode(&self) -> AccessMode {
+        self.mode
+    }
+
+    pub fn allowed(&self, info_hash: &InfoHash) -> bool {
+        let from_file = || {
+            let torrents = self.torrents.read().unwrap_or_else(|e| e.into_inner());
+            torrents.contains(info_hash)
+        };
+        match self.mode {
+            AccessMode::Open => true,
+            AccessMode::Whitelist => self.listed_contains(info_hash) || from_file(),
+            AccessMode::Blacklist => !self.listed_contains(info_hash),
+        }
+    }
+
+    /// Read the list again, returning how many hashes it holds
+    pub fn reload(&self) -> Result<usize, AccessError> {
+        let _edits = lock_edits();
+        self.read_list()
+    }
+
+    fn read_list(&self) -> Result<usize, AccessError> {
+        let hashes: HashSet<InfoHash> = match self.source {
+            None => return Ok(0),
+            Some(ListSource::File(ref path)) => {
+                let modified = fs::metadata(path)
+                    .and_then(|m| m.modified())
+                    .map_err(|e| AccessError::Io(path.clone(), e))?;
+                let text =
+                    fs::read_to_string(path).map_err(|e| AccessError::Io(path.clone(), e))?;
+                *self.modified.lock().unwrap_or_else(|e| e.into_inner()) = Some(modified);
+                parse_list(&text, path)
+            }
+            Some(ListSource::Table(ref pool)) => {
+                let rows = db_access_list(&*pool.get().map_err(StoreError::from)?)
+                    .map_err(StoreError::from)?;
+                rows.iter()
+                    .filter_map(|hex| {
+                        let hash = parse_hex_hash(hex.trim());
+                        if hash.is_none() {
+                            warn!("access_list table: {:?} is not an info hash", hex);
+                        }
+                        hash
+                    })
+                    .collect()
+            }
+        };
+
+        let count = hashes.len();
+        let mut current = self.hashes.write().unwrap_or_else(|e| e.into_inner());
+        if *current != hashes {
+            info!("Loaded {} {:?} hashes", count, self.mode);
+            *current = hashes;
+        }
+        Ok(count)
+    }
+
+    /// The hashes of the file or table, not those of .torrent files
+    pub fn listed(&self) -> Vec<InfoHash> {
+        let hashes = self.hashes.read().unwrap_or_else(|e| e.into_inner());
+        hashes.iter().cloned().collect()
+    }
+
+    /// Add a hash to the file or table and reload it, returning whether it is new
+    pub fn insert(&self, info_hash: &InfoHash) -> Result<bool, AccessError> {
+        let _edits = lock_edits();
+        if self.listed_contains(info_hash) {
+            return Ok(false);
+        }
+        let hex = to_hex(info_hash);
+        match self.source {
+            None => return Err(AccessError::NoList),
+            Some(ListSource::File(ref path)) => {
+                let mut text = read_text(path)?;
+                if !text.is_empty() && !text.ends_with('\n') {
+                    text.push('\n');
+                }
+                text.push_str(&hex);
+                text.push('\n');
+                replace_file(path, &text).map_err(|e| AccessError::Io(path.clone(), e))?;
+            }
+            Some(ListSource::Table(ref pool)) => {
+                db_access_add(&*pool.get().map_err(StoreError::from)?, &hex)
+                    .map_err(StoreError::from)?;
+            }
+        }
+        self.read_list()?;
+        Ok(true)
+    }
+
+    /// Remove a hash from the file or table and reload it, returning whether it was there.
+    /// Every line of the file holding the hash goes, notes and all.
+    pub fn remove(&self, info_hash: &InfoHash) -> Result<bool, AccessError> {
+        let _edits = lock_edits();
+        if !self.listed_contains(info_hash) {
+            return Ok(false);
+        }
+        match self.source {
+            None => return Err(AccessError::NoList),
+            Some(ListSource::File(ref path)) => {
+                let text = read_text(path)?;
+                let kept: String = text
+                    .lines()
+                    .filter(|line| line_hash(line).and_then(parse_hex_hash) != Some(*info_hash))
+                    .flat_map(|line| vec![line, "\n"])
+                    .collect();
+                replace_file(path, &kept).map_err(|e| AccessError::Io(path.clone(), e))?;
+            }
+            Some(ListSource::Table(ref pool)) => {
+                db_access_remove(&*pool.get().map_err(StoreError::from)?, &to_hex(info_hash))
+                    .map_err(StoreError::from)?;
+            }
+        }
+        self.read_list()?;
+        Ok(true)
+    }
+
+    fn listed_contains(&self, info_hash: &InfoHash) -> bool {
+        let hashes = self.hashes.read().unwrap_or_else(|e| e.into_inner());
+        hashes.contains(info_hash)
+    }
+
+    /// Replace the hashes whitelisted by .torrent files
+    pub fn set_torrents(&self, torrents: HashSet<InfoHash>) {
+        info!("{} hashes whitelisted by torrent files", torrents.len());
+        *self.torrents.write().unwrap_or_else(|e| e.into_inner()) = torrents;
+    }
+
+    /// Reload a file that changed since it was last read, or a table.
+    /// Returns the new number of hashes when anything was read.
+    pub fn refresh(&self) -> Result<Option<usize>, AccessError> {
+        if let Some(ListSource::File(ref path)) = self.source {
+            let modified = fs::metadata(path)
+                .and_then(|m| m.modified())
+                .map_err(|e| AccessError::Io(path.clone(), e))?;
+            if *self.modified.lock().unwrap_or_else(|e| e.into_inner()) == Some(modified) {
+                return Ok(None);
+            }
+        }
+        self.reload().map(Some)
+    }
+}
+
+// Write the new list next to the old one and rename it over it, so a reader never sees half of
+// it and a crash leaves one or the other
+fn replace_file(path: &Path, text: &str) -> io::Result<()> {
+    let name = path.file_name().map_or_else(|| "access".into(), |n| n.to_string_lossy());
+    let temp = path.with_file_name(format!(".{}.tmp", name));
+    fs::write(&temp, text)?;
+    let renamed = fs::metadata(path)
+        .and_then(|m| fs::set_permissions(&temp, m.permissions()))
+        .and_then(|_| fs::rename(&temp, path));
+    if renamed.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    renamed
+}
+
+fn read_text(path: &Path) -> Result<String, AccessError> {
+    fs::read_to_string(path).map_err(|e| AccessError::Io(path.to_path_buf(), e))
+}
+
+// Anything after the hash on a line is a note for whoever keeps the list
+fn line_hash(line: &str) -> Option<&str> {
+    let line = match line.find('#') {
+        Some(i) => &line[..i],
+        None => line,
+    };
+    line.split_whitespace().next()
+}
+
+fn parse_list(text: &str, path: &Path) -> HashSet<InfoHash> {
+    let mut hashes = HashSet::new();
+    for (n, line) in text.lines().enumerate() {
+        let hex = match line_hash(line) {
+            Some(x) => x,
+            None => continue,
+        };
+        match parse_hex_hash(hex) {
+            Some(hash) => {
+                hashes.insert(hash);
+            }
+            None => warn!("{} line {}: {:?} is not an info hash", path.display(), n + 1, hex),
+        }
+    }
+    hashes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn concurrent_file_edits_keep_every_hash() {
+        let path = env::temp_dir().join(format!("rtracker-access-{}.list", process::id()));
+        fs::write(&path, "# kept by hand\n").unwrap();
+        let list = Arc::new(AccessList::new(
+            AccessMode::Whitelist,
+            Some(ListSource::File(path.clone())),
+        ));
+        list.reload().unwrap();
+
+        let editors: Vec<thread::JoinHandle<()>> = (0..8u8)
+            .map(|t| {
+                let list = list.clone();
+                thread::spawn(move || {
+                    for i in 0..10u8 {
+                        let hash = [t * 16 + i; 20];
+                        assert!(list.insert(&hash).unwrap());
+                        // Every other hash comes out again, the refresh thread reads meanwhile
+                        if i % 2 == 1 {
+                            assert!(list.remove(&hash).unwrap());
+                        }
+                        list.reload().unwrap();
+                    }
+                })
+            })
+            .collect();
+        for editor in editors {
+            editor.join().unwrap();
+        }
+
+        let text = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert!(text.starts_with("# kept by hand\n"));
+        let written = parse_list(&text, &path);
+        assert_eq!(written.len(), 40);
+        for t in 0..8u8 {
+            for i in 0..10u8 {
+                let hash = [t * 16 + i; 20];
+                assert_eq!(written.contains(&hash), i % 2 == 0);
+                assert_eq!(list.allowed(&hash), i % 2 == 0);
+            }
+        }
+    }
+}
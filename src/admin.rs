@@ -0,0 +1,410 @@
+//  rtracker: bittorrent tracker
+//  Copyright (C) 2019  Justin Noah <justinnoah@gmail.com>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License.
+//
+//  This program is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//   GNU Affero General Public License for more details.
+//
+//   You should have received a copy of the GNU Affero General Public License
+//   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Admin API: look inside and steer a running tracker, on its own address behind a bearer token
+//
+//   GET    /status                          version and uptime
+//   GET    /config                          the running configuration
+//   GET    /torrents?limit=N                torrents with the most peers
+//   GET    /torrents/<hash>                 a torrent and its peers
+//   DELETE /torrents/<hash>                 forget a torrent
+//   DELETE /torrents/<hash>/peers/<addr>    remove one peer
+//   GET    /access                          the whitelist or blacklist
+//   PUT    /access/<hash>                   add to it
+//   DELETE /access/<hash>                   remove from it
+//   POST   /prune                           prune timed out peers now
+//   POST   /reload                          read the config file again, like SIGHUP
+
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time;
+
+use chrono::{Duration, Utc};
+use serde::Serialize;
+use serde_json;
+
+use access::{parse_hex_hash, to_hex, AccessError};
+use config::AccessMode;
+use handler::{self, Tracker};
+use http::{parse_request, percent_decode, read_head, write_response, Request, TIMEOUT};
+use packet_data_types::ScrapeStats;
+use reload;
+use storage::{Family, InfoHash, StoreError};
+
+const JSON: &str = "application/json";
+const DEFAULT_TOP: usize = 50;
+const MAX_TOP: usize = 1000;
+// Each connection has a thread, a few are plenty for scripts and operators
+const MAX_CONNECTIONS: usize = 8;
+// Admin clients send their request at once, a silent one is not worth waiting on
+const HEAD_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+
+enum Failure {
+    NotFound,
+    BadRequest(&'static str),
+    /// The request was fine, the tracker can not carry it out
+    Conflict(String),
+    Server(String),
+}
+
+impl From<StoreError> for Failure {
+    fn from(e: StoreError) -> Failure {
+        Failure::Server(e.to_string())
+    }
+}
+
+impl From<AccessError> for Failure {
+    fn from(e: AccessError) -> Failure {
+        match e {
+            AccessError::NoList => Failure::Conflict(e.to_string()),
+            e => Failure::Server(e.to_string()),
+        }
+    }
+}
+
+type Response = Result<Vec<u8>, Failure>;
+
+#[derive(Serialize)]
+struct Status {
+    version:        &'static str,
+    started:        String,
+    uptime_seconds: u64,
+}
+
+#[derive(Serialize)]
+struct TorrentSummary {
+    info_hash: String,
+    name:      Option<String>,
+    seeders:   i32,
+    completed: i32,
+    leechers:  i32,
+}
+
+#[derive(Serialize)]
+struct PeerInfo {
+    address: SocketAddr,
+    peer_id: String,
+    left:    i64,
+}
+
+#[derive(Serialize)]
+struct TorrentDetail {
+    #[serde(flatten)]
+    summary: TorrentSummary,
+    peers:   Vec<PeerInfo>,
+}
+
+#[derive(Serialize)]
+struct AccessListing {
+    mode:   AccessMode,
+    hashes: Vec<String>,
+}
+
+fn json<T: Serialize>(value: &T) -> Response {
+    serde_json::to_vec_pretty(value).map_err(|e| Failure::Server(e.to_string()))
+}
+
+fn hash_arg(hex: &str) -> Result<InfoHash, Failure> {
+    parse_hex_hash(hex).ok_or(Failure::BadRequest("not a 40 character hex info hash"))
+}
+
+fn summary(tracker: &Tracker, info_hash: &InfoHash, counts: ScrapeStats) -> TorrentSummary {
+    TorrentSummary {
+        info_hash: to_hex(info_hash),
+        name: tracker.store.name(info_hash).unwrap_or(None),
+        seeders: counts.seeders,
+        completed: counts.completed,
+        leechers: counts.leechers,
+    }
+}
+
+fn status(tracker: &Tracker) -> Response {
+    let uptime = tracker.started.elapsed();
+    let started = Utc::now() - Duration::from_std(uptime).unwrap_or_else(|_| Duration::zero());
+    json(&Status {
+        version: env!("CARGO_PKG_VERSION"),
+        started: started.to_rfc3339(),
+        uptime_seconds: uptime.as_secs(),
+    })
+}
+
+fn top(req: &Request, tracker: &Tracker) -> Response {
+    let limit = match req.get_str("limit") {
+        Some(l) => l.parse::<usize>().map_err(|_| Failure::BadRequest("limit is not a number"))?,
+        None => DEFAULT_TOP,
+    };
+    let torrents: Vec<TorrentSummary> = tracker
+        .store
+        .top(limit.min(MAX_TOP))?
+        .into_iter()
+        .map(|(hash, counts)| summary(tracker, &hash, counts))
+        .collect();
+    json(&torrents)
+}
+
+fn torrent(tracker: &Tracker, info_hash: &InfoHash) -> Response {
+    let store = &*tracker.store;
+    let mut peers = store.peers(info_hash, Family::V4)?;
+    peers.extend(store.peers(info_hash, Family::V6)?);
+    let counts = store.counts(info_hash)?;
+    if peers.is_empty() && counts.completed == 0 {
+        return Err(Failure::NotFound);
+    }
+
+    json(&TorrentDetail {
+        summary: summary(tracker, info_hash, counts),
+        peers: peers
+            .into_iter()
+            .map(|p| PeerInfo {
+                address: p.addr,
+                peer_id: to_hex(&p.peer_id),
+                left: p.remaining,
+            })
+            .collect(),
+    })
+}
+
+fn remove_peer(tracker: &Tracker, info_hash: &InfoHash, addr: &str) -> Response {
+    let addr = String::from_utf8_lossy(&percent_decode(addr)).into_owned();
+    let addr = SocketAddr::from_str(&addr).map_err(|_| Failure::BadRequest("not an ip:port"))?;
+    let removed = tracker.store.remove_peer(info_hash, addr)?;
+    info!("Admin removed peer {} of {}: {}", addr, to_hex(info_hash), removed);
+    json(&serde_json::json!({ "removed": removed }))
+}
+
+fn route(req: &Request, tracker: &Tracker) -> Response {
+    let segments: Vec<&str> = req.path.trim_matches('/').split('/').collect();
+    match (req.method.as_str(), &segments[..]) {
+        ("GET", ["status"]) => status(tracker),
+        ("GET", ["config"]) => json(&tracker.settings().config),
+        ("GET", ["torrents"]) => top(req, tracker),
+        ("GET", ["torrents", hash]) => torrent(tracker, &hash_arg(hash)?),
+        ("DELETE", ["torrents", hash]) => {
+            let info_hash = hash_arg(hash)?;
+            let removed = tracker.store.remove_torrent(&info_hash)?;
+            info!("Admin removed {} and its {} peers", hash, removed);
+            json(&serde_json::json!({ "removed_peers": removed }))
+        }
+        ("DELETE", ["torrents", hash, "peers", addr]) => {
+            remove_peer(tracker, &hash_arg(hash)?, addr)
+        }
+        ("GET", ["access"]) => {
+            let settings = tracker.settings();
+            json(&AccessListing {
+                mode: settings.access.mode(),
+                hashes: settings.access.listed().iter().map(to_hex).collect(),
+            })
+        }
+        ("PUT", ["access", hash]) => {
+            let added = tracker.settings().access.insert(&hash_arg(hash)?)?;
+            info!("Admin added {} to the access list: {}", hash, added);
+            json(&serde_json::json!({ "added": added }))
+        }
+        ("DELETE", ["access", hash]) => {
+            let removed = tracker.settings().access.remove(&hash_arg(hash)?)?;
+            info!("Admin removed {} from the access list: {}", hash, removed);
+            json(&serde_json::json!({ "removed": removed }))
+        }
+        ("POST", ["prune"]) => {
+            let pruned = tracker.prune()?;
+            info!("Admin pruned {} peers", pruned);
+            json(&serde_json::json!({ "pruned": pruned }))
+        }
+        ("POST", ["reload"]) => {
+            info!("Admin reloading the config");
+            // An invalid file leaves the running config alone
+            let restart_needed = reload::reload(tracker).map_err(Failure::Conflict)?;
+            json(&serde_json::json!({ "restart_needed": restart_needed }))
+        }
+        _ => Err(Failure::NotFound),
+    }
+}
+
+// Compared in full whatever the first difference, so timing tells nothing about the token
+fn authorized(req: &Request, token: &str) -> bool {
+    let given = req
+        .header("authorization")
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .unwrap_or("");
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+fn error_body(message: &str) -> Vec<u8> {
+    serde_json::to_vec(&serde_json::json!({ "error": message })).unwrap_or_default()
+}
+
+fn handle_connection(mut stream: TcpStream, src: SocketAddr, tracker: &Tracker) {
+    let _ = stream.set_read_timeout(Some(HEAD_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(TIMEOUT));
+
+    let req = match read_head(&mut stream).as_ref().and_then(|h| parse_request(h)) {
+        Some(x) => x,
+        None => {
+            write_response(&mut stream, "400 Bad Request", JSON, &error_body("bad request"));
+            return;
+        }
+    };
+    let settings = tracker.settings();
+    let token = match settings.config.admin_token.as_deref() {
+        Some(t) if !t.is_empty() => t,
+        // validate() wants a token, without one the API is closed rather than open to anyone
+        _ => {
+            warn!("Refusing admin request from {}: no token configured", src);
+            let body = error_body("no admin token configured");
+            write_response(&mut stream, "503 Service Unavailable", JSON, &body);
+            return;
+        }
+    };
+    if !authorized(&req, token) {
+        warn!("Unauthorized admin request from {}: {} {}", src, req.method, req.path);
+        write_response(&mut stream, "401 Unauthorized", JSON, &error_body("unauthorized"));
+        return;
+    }
+    debug!("Admin {} {} from {}", req.method, req.path, src);
+
+    match route(&req, tracker) {
+        Ok(body) => write_response(&mut stream, "200 OK", JSON, &body),
+        Err(Failure::NotFound) => {
+            write_response(&mut stream, "404 Not Found", JSON, &error_body("not found"))
+        }
+        Err(Failure::BadRequest(reason)) => {
+            write_response(&mut stream, "400 Bad Request", JSON, &error_body(reason))
+        }
+        Err(Failure::Conflict(reason)) => {
+            write_response(&mut stream, "409 Conflict", JSON, &error_body(&reason))
+        }
+        Err(Failure::Server(reason)) => {
+            warn!("Admin {} {} failed: {}", req.method, req.path, reason);
+            let body = error_body(&reason);
+            write_response(&mut stream, "500 Internal Server Error", JSON, &body)
+        }
+    }
+}
+
+/// Answer admin requests on `listener`, each connection on its own thread, until the tracker
+/// is stopping
+pub fn serve(listener: TcpListener, tracker: Arc<Tracker>) {
+    let active = Arc::new(AtomicUsize::new(0));
+    while let Some(accepted) = handler::accept(&listener, &tracker) {
+        let (stream, src) = match accepted {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to accept an admin connection: {}", e);
+                continue;
+            }
+        };
+        if active.load(Ordering::Relaxed) >= MAX_CONNECTIONS {
+            warn!("Too many admin connections, dropping {}", src);
+            continue;
+        }
+
+        active.fetch_add(1, Ordering::Relaxed);
+        let tracker = tracker.clone();
+        let active = active.clone();
+        thread::spawn(move || {
+            handle_connection(stream, src, &tracker);
+            active.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+
+    // A prune or reload in progress finishes before the store is flushed
+    while active.load(Ordering::Relaxed) > 0 {
+        thread::sleep(handler::ACCEPT_POLL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::sync::RwLock;
+    use std::time::Instant;
+
+    use super::*;
+    use access::AccessList;
+    use blocklist::Blocklist;
+    use config::ServerConfig;
+    use handler::tests::tracker;
+    use handler::Settings;
+    use memory::MemoryStore;
+
+    // An admin API served with `token`, stopped when the tracker is dropped
+    fn admin(token: Option<&str>) -> (Arc<Tracker>, SocketAddr) {
+        let mut tracker = tracker(Arc::new(MemoryStore::new(4)));
+        let config = ServerConfig {
+            admin_token: token.map(String::from),
+            ..ServerConfig::default()
+        };
+        let settings = Settings {
+            config,
+            access: AccessList::open(),
+            blocklist: Blocklist::default(),
+        };
+        tracker.settings = RwLock::new(Arc::new(settings));
+        let tracker = Arc::new(tracker);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serve_tracker = tracker.clone();
+        thread::spawn(move || serve(listener, serve_tracker));
+        (tracker, addr)
+    }
+
+    // The status line of the answer to GET /status with `auth` as the Authorization header
+    fn status(addr: SocketAddr, auth: Option<&str>) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let header = auth.map_or(String::new(), |a| format!("Authorization: {}\r\n", a));
+        write!(stream, "GET /status HTTP/1.1\r\n{}\r\n", header).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[test]
+    fn bearer_token_is_required() {
+        let (tracker, addr) = admin(Some("secret"));
+        assert_eq!(status(addr, Some("Bearer secret")), "HTTP/1.1 200 OK");
+        assert_eq!(status(addr, Some("Bearer secreT")), "HTTP/1.1 401 Unauthorized");
+        assert_eq!(status(addr, None), "HTTP/1.1 401 Unauthorized");
+        tracker.stopping.store(true, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn missing_token_refuses_everyone() {
+        for token in &[None, Some("")] {
+            let (tracker, addr) = admin(*token);
+            assert_eq!(status(addr, None), "HTTP/1.1 503 Service Unavailable");
+            assert_eq!(status(addr, Some("Bearer ")), "HTTP/1.1 503 Service Unavailable");
+            tracker.stopping.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn silent_client_does_not_block_others() {
+        let (tracker, addr) = admin(Some("secret"));
+        let _silent = TcpStream::connect(addr).unwrap();
+        let started = Instant::now();
+        assert_eq!(status(addr, Some("Bearer secret")), "HTTP/1.1 200 OK");
+        assert!(started.elapsed() < HEAD_TIMEOUT);
+        tracker.stopping.store(true, Ordering::Relaxed);
+    }
+}
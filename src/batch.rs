@@ -0,0 +1,135 @@
+//  rtracker: bittorrent tracker
+//  Copyright (C) 2019  Justin Noah <justinnoah@gmail.com>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License.
+//
+//  This program is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//   GNU Affero General Public License for more details.
+//
+//   You should have received a copy of the GNU Affero General Public License
+//   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// recvmmsg / sendmmsg: a batch of datagrams per syscall instead of one, Linux only
+
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::Arc;
+
+use libc::{c_uint, iovec, mmsghdr, sockaddr_storage, socklen_t, MSG_WAITFORONE};
+use socket2::SockAddr;
+
+use handler::{handle_datagram, timed_out, Tracker, MAX_PACKET_SIZE};
+
+fn empty_msg() -> mmsghdr {
+    // All zeros is a valid mmsghdr, null pointers included
+    unsafe { mem::zeroed() }
+}
+
+/// Answer packets arriving on `sock` until the tracker is stopping, up to `batch_size` at a time.
+/// Blocks until at least one packet arrives, then takes whatever else is already queued.
+pub fn serve(sock: UdpSocket, tracker: Arc<Tracker>) {
+    let batch = tracker.settings().config.batch_size;
+    let fd = sock.as_raw_fd();
+    let mut bufs = vec![[0u8; MAX_PACKET_SIZE]; batch];
+    let mut addrs: Vec<sockaddr_storage> = vec![unsafe { mem::zeroed() }; batch];
+    let mut iovecs: Vec<iovec> = bufs
+        .iter_mut()
+        .map(|b| iovec {
+            iov_base: b.as_mut_ptr() as *mut _,
+            iov_len: b.len(),
+        })
+        .collect();
+    let mut msgs: Vec<mmsghdr> = (0..batch).map(|_| empty_msg()).collect();
+    for ((msg, iov), addr) in msgs.iter_mut().zip(iovecs.iter_mut()).zip(addrs.iter_mut()) {
+        msg.msg_hdr.msg_iov = iov;
+        msg.msg_hdr.msg_iovlen = 1;
+        msg.msg_hdr.msg_name = addr as *mut _ as *mut _;
+    }
+    let mut replies: Vec<(Vec<u8>, SockAddr)> = Vec::with_capacity(batch);
+
+    while !tracker.is_stopping() {
+        // The kernel shrinks the address lengths to what it wrote, give them back the room
+        for msg in msgs.iter_mut() {
+            msg.msg_hdr.msg_namelen = mem::size_of::<sockaddr_storage>() as socklen_t;
+        }
+        let received = unsafe {
+            libc::recvmmsg(fd, msgs.as_mut_ptr(), batch as c_uint, MSG_WAITFORONE, ptr::null_mut())
+        };
+        if received < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted && !timed_out(&e) {
+                warn!("Failed to receive packets: {}", e);
+            }
+            continue;
+        }
+
+        for i in 0..received as usize {
+            let src = unsafe { SockAddr::new(addrs[i], msgs[i].msg_hdr.msg_namelen) };
+            let src_addr: SocketAddr = match src.as_socket() {
+                Some(x) => x,
+                None => continue,
+            };
+            let amt = msgs[i].msg_len as usize;
+            if let Some(reply) = handle_datagram(&bufs[i][..amt], src_addr, &tracker) {
+                replies.push((reply, src));
+            }
+        }
+
+        send_all(fd, &replies);
+        replies.clear();
+    }
+}
+
+// Send every reply, as few syscalls as the kernel allows
+fn send_all(fd: RawFd, replies: &[(Vec<u8>, SockAddr)]) {
+    let mut iovecs: Vec<iovec> = replies
+        .iter()
+        .map(|r| iovec {
+            iov_base: r.0.as_ptr() as *mut _,
+            iov_len: r.0.len(),
+        })
+        .collect();
+    let mut msgs: Vec<mmsghdr> = replies
+        .iter()
+        .zip(iovecs.iter_mut())
+        .map(|(r, iov)| {
+            let mut msg = empty_msg();
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg.msg_hdr.msg_name = r.1.as_ptr() as *mut _;
+            msg.msg_hdr.msg_namelen = r.1.len();
+            msg
+        })
+        .collect();
+
+    let mut sent = 0;
+    while sent < msgs.len() {
+        let n = unsafe {
+            libc::sendmmsg(fd, msgs[sent..].as_mut_ptr(), (msgs.len() - sent) as c_uint, 0)
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            // Only the first message failed, skip it and carry on with the rest
+            let (packet, dest) = &replies[sent];
+            warn!(
+                "Failed to send {} bytes to {:?}: {}",
+                packet.len(),
+                dest.as_socket(),
+                e
+            );
+            sent += 1;
+        } else {
+            sent += n as usize;
+        }
+    }
+}
@@ -0,0 +1,1126 @@
+//  rtracker: bittorrent tracker
+//  Copyright (C) 2019  Justin Noah <justinnoah@gmail.com>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License.
+//
+//  This program is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//   GNU Affero General Public License for more details.
+//
+//   You should have received a copy of the GNU Affero General Public License
+//   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Bencode, as described by BEP 3.
+//
+// Only canonical input is accepted: integers without leading zeros or -0, string lengths without
+// leading zeros, and dictionary keys unique and sorted as raw bytes. Encoding always produces
+// canonical output, so decode(encode(v)) == v and encode(decode(b)) == b for any accepted b.
+
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+
+use serde::de::{self, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+use serde::ser::{self, Serialize};
+
+// Deeper nesting than this is an attack, not a torrent
+const MAX_DEPTH: usize = 256;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Malformed input, with the byte offset it was found at
+    Syntax { position: usize, reason: &'static str },
+    /// The value does not fit the Rust type, or the Rust type has no bencode form
+    Message(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Syntax { position, reason } => write!(f, "{} at byte {}", reason, position),
+            Error::Message(ref m) => write!(f, "{}", m),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::Message(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::Message(msg.to_string())
+    }
+}
+
+impl Value {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_to(&mut out);
+        out
+    }
+
+    pub fn encode_to(&self, out: &mut Vec<u8>) {
+        match *self {
+            Value::Int(i) => {
+                out.push(b'i');
+                out.extend_from_slice(i.to_string().as_bytes());
+                out.push(b'e');
+            }
+            Value::Bytes(ref b) => encode_bytes(out, b),
+            Value::List(ref l) => {
+                out.push(b'l');
+                for v in l {
+                    v.encode_to(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(ref d) => {
+                // BTreeMap iterates in raw byte order, which is the canonical key order
+                out.push(b'd');
+                for (k, v) in d {
+                    encode_bytes(out, k);
+                    v.encode_to(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Value {
+        Value::Int(i)
+    }
+}
+
+impl<'a> From<&'a [u8]> for Value {
+    fn from(b: &'a [u8]) -> Value {
+        Value::Bytes(b.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(b: Vec<u8>) -> Value {
+        Value::Bytes(b)
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(s: &'a str) -> Value {
+        Value::Bytes(s.as_bytes().to_vec())
+    }
+}
+
+fn encode_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(bytes);
+}
+
+/// Decode exactly one value spanning all of `input`
+pub fn decode(input: &[u8]) -> Result<Value, Error> {
+    let mut decoder = Decoder::new(input);
+    let value = decoder.value()?;
+    if decoder.pos != input.len() {
+        return Err(decoder.error("trailing data"));
+    }
+    Ok(value)
+}
+
+/// Decoder over a byte slice that also exposes where each value was found, for callers that
+/// need the exact bytes of a value (e.g. hashing a torrent's info dictionary)
+pub struct Decoder<'a> {
+    input: &'a [u8],
+    pos:   usize,
+    depth: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(input: &'a [u8]) -> Decoder<'a> {
+        Decoder {
+            input,
+            pos: 0,
+            depth: 0,
+        }
+    }
+
+    fn error(&self, reason: &'static str) -> Error {
+        Error::Syntax {
+            position: self.pos,
+            reason,
+        }
+    }
+
+    fn peek(&self) -> Result<u8, Error> {
+        match self.input.get(self.pos) {
+            Some(&b) => Ok(b),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    // Digits up to `end`, with an optional leading '-', in canonical form
+    fn number(&mut self, end: u8, signed: bool) -> Result<i64, Error> {
+        let start = self.pos;
+        let negative = signed && self.peek()? == b'-';
+        if negative {
+            self.pos += 1;
+        }
+
+        let digits_start = self.pos;
+        while self.peek()?.is_ascii_digit() {
+            self.pos += 1;
+        }
+        let digits = &self.input[digits_start..self.pos];
+
+        if self.peek()? != end {
+            return Err(self.error("unexpected byte in number"));
+        }
+        if digits.is_empty() {
+            return Err(self.error("number without digits"));
+        }
+        if digits[0] == b'0' && (digits.len() > 1 || negative) {
+            self.pos = digits_start;
+            return Err(self.error("non-canonical number"));
+        }
+
+        // Only ASCII digits (and '-') were consumed, so this is valid UTF-8
+        let text = ::std::str::from_utf8(&self.input[start..self.pos]).unwrap();
+        let n = match text.parse::<i64>() {
+            Ok(n) => n,
+            Err(_) => {
+                self.pos = start;
+                return Err(self.error("number out of range"));
+            }
+        };
+        // Skip the terminator
+        self.pos += 1;
+        Ok(n)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        if !self.peek()?.is_ascii_digit() {
+            return Err(self.error("expected a string"));
+        }
+        let start = self.pos;
+        let len = self.number(b':', false)? as usize;
+        if self.input.len() - self.pos < len {
+            self.pos = start;
+            return Err(self.error("string longer than the input"));
+        }
+        let bytes = &self.input[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Decode the next value
+    pub fn value(&mut self) -> Result<Value, Error> {
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                Ok(Value::Int(self.number(b'e', true)?))
+            }
+            b'0'..=b'9' => Ok(Value::Bytes(self.bytes()?.to_vec())),
+            b'l' => {
+                self.enter()?;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.value()?);
+                }
+                self.leave();
+                Ok(Value::List(list))
+            }
+            b'd' => {
+                self.enter()?;
+                let mut dict = BTreeMap::new();
+                let mut last: Option<&[u8]> = None;
+                while self.peek()? != b'e' {
+                    let key_pos = self.pos;
+                    let key = self.bytes()?;
+                    if let Some(prev) = last {
+                        if key <= prev {
+                            self.pos = key_pos;
+                            return Err(self.error("dictionary keys unsorted or duplicated"));
+                        }
+                    }
+                    last = Some(key);
+                    dict.insert(key.to_vec(), self.value()?);
+                }
+                self.leave();
+                Ok(Value::Dict(dict))
+            }
+            _ => Err(self.error("unexpected byte")),
+        }
+    }
+
+    /// Decode a dictionary, returning the exact input bytes of the value under `key`, if any
+    pub fn raw_dict_value(&mut self, key: &[u8]) -> Result<Option<&'a [u8]>, Error> {
+        if self.peek()? != b'd' {
+            return Err(self.error("expected a dictionary"));
+        }
+        self.enter()?;
+        let mut found = None;
+        let mut last: Option<&[u8]> = None;
+        while self.peek()? != b'e' {
+            let key_pos = self.pos;
+            let k = self.bytes()?;
+            if let Some(prev) = last {
+                if k <= prev {
+                    self.pos = key_pos;
+                    return Err(self.error("dictionary keys unsorted or duplicated"));
+                }
+            }
+            last = Some(k);
+
+            let start = self.pos;
+            self.value()?;
+            if k == key {
+                found = Some(&self.input[start..self.pos]);
+            }
+        }
+        self.leave();
+        Ok(found)
+    }
+
+    fn enter(&mut self) -> Result<(), Error> {
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+        self.depth += 1;
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        self.depth -= 1;
+        self.pos += 1;
+    }
+}
+
+/// Serialize any `Serialize` type to canonical bencode.
+///
+/// `None` fields of structs and maps are left out, bencode has no null.
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error> {
+    Ok(to_value(value)?.encode())
+}
+
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value, Error> {
+    match value.serialize(ValueSerializer)? {
+        Some(v) => Ok(v),
+        None => Err(Error::Message(String::from("no bencode form for a lone None or ()"))),
+    }
+}
+
+/// Deserialize any `Deserialize` type from bencode
+pub fn from_bytes<T: de::DeserializeOwned>(input: &[u8]) -> Result<T, Error> {
+    from_value(decode(input)?)
+}
+
+pub fn from_value<T: de::DeserializeOwned>(value: Value) -> Result<T, Error> {
+    T::deserialize(value)
+}
+
+// Serializes to Some(Value), or None for the things bencode can not hold (None and unit), which
+// dictionaries then leave out
+struct ValueSerializer;
+
+fn int<T: Into<i64>>(i: T) -> Result<Option<Value>, Error> {
+    Ok(Some(Value::Int(i.into())))
+}
+
+fn no_float() -> Result<Option<Value>, Error> {
+    Err(Error::Message(String::from("bencode has no floating point numbers")))
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Option<Value>;
+    type Error = Error;
+    type SerializeSeq = SeqSerializer;
+    type SerializeTuple = SeqSerializer;
+    type SerializeTupleStruct = SeqSerializer;
+    type SerializeTupleVariant = VariantSerializer<SeqSerializer>;
+    type SerializeMap = MapSerializer;
+    type SerializeStruct = MapSerializer;
+    type SerializeStructVariant = VariantSerializer<MapSerializer>;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Error> {
+        int(v as i64)
+    }
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, Error> {
+        int(v)
+    }
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, Error> {
+        int(v)
+    }
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, Error> {
+        int(v)
+    }
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Error> {
+        int(v)
+    }
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, Error> {
+        int(v)
+    }
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, Error> {
+        int(v)
+    }
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, Error> {
+        int(v)
+    }
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, Error> {
+        if v > i64::MAX as u64 {
+            return Err(Error::Message(format!("{} does not fit a bencode integer", v)));
+        }
+        int(v as i64)
+    }
+    fn serialize_f32(self, _v: f32) -> Result<Self::Ok, Error> {
+        no_float()
+    }
+    fn serialize_f64(self, _v: f64) -> Result<Self::Ok, Error> {
+        no_float()
+    }
+    fn serialize_char(self, v: char) -> Result<Self::Ok, Error> {
+        let mut buf = [0u8; 4];
+        Ok(Some(Value::from(&*v.encode_utf8(&mut buf))))
+    }
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, Error> {
+        Ok(Some(Value::from(v)))
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Error> {
+        Ok(Some(Value::from(v)))
+    }
+    fn serialize_none(self) -> Result<Self::Ok, Error> {
+        Ok(None)
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, Error> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<Self::Ok, Error> {
+        Ok(None)
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Error> {
+        Ok(None)
+    }
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Error> {
+        Ok(Some(Value::from(variant)))
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Error> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Error> {
+        let mut d = BTreeMap::new();
+        if let Some(v) = value.serialize(self)? {
+            d.insert(variant.as_bytes().to_vec(), v);
+        }
+        Ok(Some(Value::Dict(d)))
+    }
+    fn serialize_seq(self, len: Option<usize>) -> Result<SeqSerializer, Error> {
+        Ok(SeqSerializer(Vec::with_capacity(len.unwrap_or(0))))
+    }
+    fn serialize_tuple(self, len: usize) -> Result<SeqSerializer, Error> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SeqSerializer, Error> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Ok(VariantSerializer {
+            variant,
+            inner: self.serialize_seq(Some(len))?,
+        })
+    }
+    fn serialize_map(self, _len: Option<usize>) -> Result<MapSerializer, Error> {
+        Ok(MapSerializer {
+            map: BTreeMap::new(),
+            key: None,
+        })
+    }
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<MapSerializer, Error> {
+        self.serialize_map(Some(len))
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Ok(VariantSerializer {
+            variant,
+            inner: self.serialize_map(Some(len))?,
+        })
+    }
+}
+
+struct SeqSerializer(Vec<Value>);
+
+impl SeqSerializer {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        match value.serialize(ValueSerializer)? {
+            Some(v) => {
+                self.0.push(v);
+                Ok(())
+            }
+            None => Err(Error::Message(String::from("bencode lists can not hold None or ()"))),
+        }
+    }
+}
+
+impl ser::SerializeSeq for SeqSerializer {
+    type Ok = Option<Value>;
+    type Error = Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(Some(Value::List(self.0)))
+    }
+}
+
+impl ser::SerializeTuple for SeqSerializer {
+    type Ok = Option<Value>;
+    type Error = Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Self::Ok, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SeqSerializer {
+    type Ok = Option<Value>;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Self::Ok, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+struct MapSerializer {
+    map: BTreeMap<Vec<u8>, Value>,
+    key: Option<Vec<u8>>,
+}
+
+impl MapSerializer {
+    fn insert<T: Serialize + ?Sized>(&mut self, key: Vec<u8>, value: &T) -> Result<(), Error> {
+        if let Some(v) = value.serialize(ValueSerializer)? {
+            self.map.insert(key, v);
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for MapSerializer {
+    type Ok = Option<Value>;
+    type Error = Error;
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        match key.serialize(ValueSerializer)? {
+            Some(Value::Bytes(k)) => {
+                self.key = Some(k);
+                Ok(())
+            }
+            _ => Err(Error::Message(String::from("bencode dictionary keys must be strings"))),
+        }
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        match self.key.take() {
+            Some(k) => self.insert(k, value),
+            None => Err(Error::Message(String::from("map value without a key"))),
+        }
+    }
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(Some(Value::Dict(self.map)))
+    }
+}
+
+impl ser::SerializeStruct for MapSerializer {
+    type Ok = Option<Value>;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.insert(key.as_bytes().to_vec(), value)
+    }
+    fn end(self) -> Result<Self::Ok, Error> {
+        ser::SerializeMap::end(self)
+    }
+}
+
+// Enum variants with data become a single entry dictionary: { variant: data }
+struct VariantSerializer<S> {
+    variant: &'static str,
+    inner:   S,
+}
+
+impl<S> VariantSerializer<S> {
+    fn wrap(variant: &'static str, inner: Option<Value>) -> Option<Value> {
+        let mut d = BTreeMap::new();
+        if let Some(v) = inner {
+            d.insert(variant.as_bytes().to_vec(), v);
+        }
+        Some(Value::Dict(d))
+    }
+}
+
+impl ser::SerializeTupleVariant for VariantSerializer<SeqSerializer> {
+    type Ok = Option<Value>;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.inner.push(value)
+    }
+    fn end(self) -> Result<Self::Ok, Error> {
+        let inner = ser::SerializeSeq::end(self.inner)?;
+        Ok(Self::wrap(self.variant, inner))
+    }
+}
+
+impl ser::SerializeStructVariant for VariantSerializer<MapSerializer> {
+    type Ok = Option<Value>;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.inner.insert(key.as_bytes().to_vec(), value)
+    }
+    fn end(self) -> Result<Self::Ok, Error> {
+        let inner = ser::SerializeMap::end(self.inner)?;
+        Ok(Self::wrap(self.variant, inner))
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::{SerializeMap, SerializeSeq};
+        match *self {
+            Value::Int(i) => serializer.serialize_i64(i),
+            Value::Bytes(ref b) => serializer.serialize_bytes(b),
+            Value::List(ref l) => {
+                let mut seq = serializer.serialize_seq(Some(l.len()))?;
+                for v in l {
+                    seq.serialize_element(v)?;
+                }
+                seq.end()
+            }
+            Value::Dict(ref d) => {
+                let mut map = serializer.serialize_map(Some(d.len()))?;
+                for (k, v) in d {
+                    map.serialize_entry(&Bytes(k), v)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+/// Serializes as a byte string rather than as a list of integers, as a `&[u8]` field would
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Bytes<'a>(pub &'a [u8]);
+
+impl<'a> Serialize for Bytes<'a> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+impl<'de> de::Deserialize<'de> for Value {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a bencode value")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Value, E> {
+        Ok(Value::Int(v as i64))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::Int(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Value, E> {
+        if v > i64::MAX as u64 {
+            return Err(E::custom(format!("{} does not fit a bencode integer", v)));
+        }
+        Ok(Value::Int(v as i64))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::from(v))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Value, E> {
+        Ok(Value::from(v))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Value, E> {
+        Ok(Value::Bytes(v))
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut list = Vec::new();
+        while let Some(v) = seq.next_element()? {
+            list.push(v);
+        }
+        Ok(Value::List(list))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut dict = BTreeMap::new();
+        while let Some((k, v)) = map.next_entry::<Value, Value>()? {
+            match k {
+                Value::Bytes(k) => {
+                    dict.insert(k, v);
+                }
+                _ => return Err(de::Error::custom("bencode dictionary keys must be strings")),
+            }
+        }
+        Ok(Value::Dict(dict))
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for Value {
+    type Deserializer = Value;
+    fn into_deserializer(self) -> Value {
+        self
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Value {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::Int(i) => visitor.visit_i64(i),
+            Value::Bytes(b) => visitor.visit_byte_buf(b),
+            Value::List(l) => visitor.visit_seq(de::value::SeqDeserializer::new(l.into_iter())),
+            Value::Dict(d) => visitor.visit_map(de::value::MapDeserializer::new(
+                d.into_iter().map(|(k, v)| (Value::Bytes(k), v)),
+            )),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::Int(0) => visitor.visit_bool(false),
+            Value::Int(1) => visitor.visit_bool(true),
+            other => other.deserialize_any(visitor),
+        }
+    }
+
+    // Strings are byte strings, only turned into text when the target type asks for it
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::Bytes(b) => match String::from_utf8(b) {
+                Ok(s) => visitor.visit_string(s),
+                Err(_) => Err(Error::Message(String::from("string is not valid UTF-8"))),
+            },
+            other => other.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    // A present value is always Some, absent struct fields become None
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // Lets Vec<u8> and [u8; N] read a byte string
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::Bytes(b) => visitor.visit_seq(de::value::SeqDeserializer::new(b.into_iter())),
+            other => other.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self {
+            Value::Bytes(_) => visitor.visit_enum(EnumAccess {
+                variant: self,
+                value: None,
+            }),
+            Value::Dict(d) => {
+                if d.len() != 1 {
+                    return Err(Error::Message(String::from(
+                        "an enum must be a dictionary with a single key",
+                    )));
+                }
+                let (k, v) = d.into_iter().next().unwrap();
+                visitor.visit_enum(EnumAccess {
+                    variant: Value::Bytes(k),
+                    value: Some(v),
+                })
+            }
+            _ => Err(Error::Message(String::from("expected an enum"))),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char bytes byte_buf unit unit_struct
+        tuple_struct map struct ignored_any
+    }
+}
+
+struct EnumAccess {
+    variant: Value,
+    value:   Option<Value>,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumAccess {
+    type Error = Error;
+    type Variant = VariantAccess;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, VariantAccess), Error> {
+        let variant = seed.deserialize(self.variant)?;
+        Ok((variant, VariantAccess(self.value)))
+    }
+}
+
+struct VariantAccess(Option<Value>);
+
+impl VariantAccess {
+    fn value(self) -> Result<Value, Error> {
+        self.0
+            .ok_or_else(|| Error::Message(String::from("enum variant has no data")))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for VariantAccess {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self.value()?)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(self.value()?, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_map(self.value()?, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    fn syntax_error(input: &[u8]) -> (usize, &'static str) {
+        match decode(input) {
+            Err(Error::Syntax { position, reason }) => (position, reason),
+            Ok(v) => panic!("{:?} decoded to {:?}", String::from_utf8_lossy(input), v),
+            Err(e) => panic!("{:?} is not a syntax error", e),
+        }
+    }
+
+    fn random_bytes(rng: &mut StdRng) -> Vec<u8> {
+        let len = rng.gen_range(0..12);
+        // A small alphabet, so dictionary keys collide and share prefixes
+        (0..len).map(|_| rng.gen_range(b'`'..b'f')).collect()
+    }
+
+    fn random_value(rng: &mut StdRng, depth: usize) -> Value {
+        let kind = if depth == 0 { rng.gen_range(0..2) } else { rng.gen_range(0..4) };
+        match kind {
+            0 => Value::Int(match rng.gen_range(0..4) {
+                0 => rng.gen(),
+                1 => rng.gen_range(-20..20),
+                2 => i64::MIN,
+                _ => i64::MAX,
+            }),
+            1 => Value::Bytes(random_bytes(rng)),
+            2 => {
+                let len = rng.gen_range(0..5);
+                Value::List((0..len).map(|_| random_value(rng, depth - 1)).collect())
+            }
+            _ => {
+                let len = rng.gen_range(0..5);
+                let pairs = (0..len).map(|_| (random_bytes(rng), random_value(rng, depth - 1)));
+                Value::Dict(pairs.collect())
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut rng = StdRng::seed_from_u64(13);
+        for _ in 0..5000 {
+            let value = random_value(&mut rng, 4);
+            let encoded = value.encode();
+            assert_eq!(decode(&encoded).unwrap(), value);
+            assert_eq!(decode(&encoded).unwrap().encode(), encoded);
+            // Through serde, Value is its own data model
+            assert_eq!(to_value(&value).unwrap(), value);
+            assert_eq!(from_bytes::<Value>(&encoded).unwrap(), value);
+        }
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    enum Layout {
+        Single,
+        Multi { files: Vec<FileEntry> },
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct FileEntry {
+        length: u64,
+        path:   Vec<String>,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Meta {
+        name:    String,
+        #[serde(rename = "piece length")]
+        piece:   u32,
+        offset:  i32,
+        private: bool,
+        comment: Option<String>,
+        layout:  Layout,
+    }
+
+    #[test]
+    fn serde_round_trips() {
+        let meta = Meta {
+            name:    String::from("a"),
+            piece:   16384,
+            offset:  -1,
+            private: true,
+            comment: None,
+            layout:  Layout::Multi {
+                files: vec![FileEntry {
+                    length: 3,
+                    path:   vec![String::from("b"), String::from("c")],
+                }],
+            },
+        };
+        // Keys sorted, the None comment left out, the variant a single entry dictionary
+        let encoded: &[u8] = b"d6:layoutd5:Multid5:filesld6:lengthi3e4:pathl1:b1:ceeeee\
+                               4:name1:a6:offseti-1e12:piece lengthi16384e7:privatei1ee";
+        assert_eq!(to_bytes(&meta).unwrap(), encoded);
+        assert_eq!(from_bytes::<Meta>(encoded).unwrap(), meta);
+
+        let single = Meta {
+            comment: Some(String::from("x")),
+            layout: Layout::Single,
+            ..meta
+        };
+        assert_eq!(from_bytes::<Meta>(&to_bytes(&single).unwrap()).unwrap(), single);
+        assert_eq!(to_bytes(&Bytes(b"ab")).unwrap(), b"2:ab");
+        assert_eq!(from_bytes::<Vec<u8>>(b"2:ab").unwrap(), b"ab");
+    }
+
+    fn message(result: Result<impl fmt::Debug, Error>) -> String {
+        match result {
+            Err(Error::Message(m)) => m,
+            other => panic!("expected a message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn serde_rejects_what_bencode_can_not_hold() {
+        assert_eq!(message(to_bytes(&1.5f64)), "bencode has no floating point numbers");
+        assert!(message(to_bytes(&u64::MAX)).contains("does not fit"));
+        let mut by_int = BTreeMap::new();
+        by_int.insert(1, 2);
+        assert_eq!(message(to_bytes(&by_int)), "bencode dictionary keys must be strings");
+        assert_eq!(message(to_bytes(&vec![None::<i32>])), "bencode lists can not hold None or ()");
+        assert_eq!(message(to_bytes(&())), "no bencode form for a lone None or ()");
+
+        assert!(message(from_bytes::<u8>(b"i256e")).contains("256"));
+        assert_eq!(message(from_bytes::<String>(b"2:\xff\xfe")), "string is not valid UTF-8");
+        assert!(message(from_bytes::<FileEntry>(b"d6:lengthi3ee")).contains("path"));
+        // Malformed input is still reported where it is
+        match from_bytes::<FileEntry>(b"d6:lengthi03ee") {
+            Err(Error::Syntax { position, .. }) => assert_eq!(position, 10),
+            other => panic!("expected a syntax error, got {:?}", other),
+        }
+    }
+
+    // Whatever a damaged encoding decodes to, it is canonical, and nothing panics
+    #[test]
+    fn fuzz_accepts_only_canonical_input() {
+        let mut rng = StdRng::seed_from_u64(15);
+        let alphabet = b"0123456789-:deil";
+        for _ in 0..20000 {
+            let mut input = random_value(&mut rng, 3).encode();
+            for _ in 0..rng.gen_range(1..4) {
+                let at = rng.gen_range(0..=input.len());
+                match rng.gen_range(0..4) {
+                    0 => input.truncate(at),
+                    1 => input.insert(at, alphabet[rng.gen_range(0..alphabet.len())]),
+                    2 if at < input.len() => {
+                        input[at] = alphabet[rng.gen_range(0..alphabet.len())];
+                    }
+                    _ if at < input.len() => {
+                        input.remove(at);
+                    }
+                    _ => (),
+                }
+            }
+            match decode(&input) {
+                Ok(value) => assert_eq!(value.encode(), input),
+                Err(Error::Syntax { position, .. }) => assert!(position <= input.len()),
+                Err(e) => panic!("{:?} is not a syntax error", e),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_non_canonical_integers() {
+        assert_eq!(syntax_error(b"i03e"), (1, "non-canonical number"));
+        assert_eq!(syntax_error(b"i-0e"), (2, "non-canonical number"));
+        assert_eq!(syntax_error(b"i00e"), (1, "non-canonical number"));
+        assert_eq!(syntax_error(b"li1ei-01ee"), (6, "non-canonical number"));
+        assert_eq!(syntax_error(b"03:abc"), (0, "non-canonical number"));
+        assert_eq!(syntax_error(b"ie"), (1, "number without digits"));
+        assert_eq!(syntax_error(b"i-e"), (2, "number without digits"));
+        assert_eq!(syntax_error(b"i+1e"), (1, "unexpected byte in number"));
+        assert_eq!(syntax_error(b"i9223372036854775808e"), (1, "number out of range"));
+        assert_eq!(decode(b"i-9223372036854775808e").unwrap(), Value::Int(i64::MIN));
+    }
+
+    #[test]
+    fn rejects_unsorted_keys() {
+        let unsorted = "dictionary keys unsorted or duplicated";
+        assert_eq!(syntax_error(b"d1:bi1e1:ai2ee"), (7, unsorted));
+        assert_eq!(syntax_error(b"d1:ai1e1:ai2ee"), (7, unsorted));
+        // Raw byte order, a prefix sorts first
+        assert_eq!(syntax_error(b"d2:abi1e1:ai2ee"), (8, unsorted));
+        assert!(decode(b"d1:ai1e2:abi2ee").is_ok());
+
+        let error = Decoder::new(b"d1:bi1e1:ai2ee").raw_dict_value(b"a").unwrap_err();
+        assert_eq!(error.to_string(), format!("{} at byte 7", unsorted));
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth: usize| {
+            let mut input = vec![b'l'; depth];
+            input.extend(vec![b'e'; depth]);
+            input
+        };
+        assert!(decode(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(syntax_error(&nested(MAX_DEPTH + 1)), (MAX_DEPTH, "nested too deeply"));
+        let mut dicts = b"d1:a".repeat(MAX_DEPTH + 1);
+        dicts.extend(vec![b'e'; MAX_DEPTH + 1]);
+        assert_eq!(syntax_error(&dicts), (MAX_DEPTH * 4, "nested too deeply"));
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let end = "unexpected end of input";
+        assert_eq!(syntax_error(b""), (0, end));
+        assert_eq!(syntax_error(b"i12"), (3, end));
+        assert_eq!(syntax_error(b"l"), (1, end));
+        assert_eq!(syntax_error(b"li1e"), (4, end));
+        assert_eq!(syntax_error(b"d1:a"), (4, end));
+        assert_eq!(syntax_error(b"3:"), (0, "string longer than the input"));
+        assert_eq!(syntax_error(b"5:abc"), (0, "string longer than the input"));
+        assert_eq!(syntax_error(b"i1ei2e"), (3, "trailing data"));
+    }
+
+    #[test]
+    fn finds_raw_dict_values() {
+        let input = b"d4:infod6:lengthi3ee4:name1:xe";
+        let raw = Decoder::new(input).raw_dict_value(b"info").unwrap();
+        assert_eq!(raw, Some(&b"d6:lengthi3ee"[..]));
+        assert_eq!(Decoder::new(input).raw_dict_value(b"nope").unwrap(), None);
+    }
+}
@@ -0,0 +1,313 @@
+//  rtracker: bittorrent tracker
+//  Copyright (C) 2019  Justin Noah <justinnoah@gmail.com>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License.
+//
+//  This program is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//   GNU Affero General Public License for more details.
+//
+//   You should have received a copy of the GNU Affero General Public License
+//   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Banned networks, from any mix of these line formats:
+//
+//   CIDR or single address    10.0.0.0/8, 2001:db8::/32, 192.0.2.1
+//   eMule ipfilter.dat        001.002.003.000 - 001.002.003.255 , 000 , Some network
+//   PeerGuardian P2P          Some network:1.2.3.0-1.2.3.255
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// ipfilter.dat entries at or above this access level are allowed rather than blocked
+const DAT_ALLOW_LEVEL: u32 = 128;
+
+#[derive(Debug)]
+pub struct BlocklistError(PathBuf, io::Error);
+
+impl fmt::Display for BlocklistError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.0.display(), self.1)
+    }
+}
+
+/// Blocked addresses as sorted, non-overlapping, inclusive ranges.
+/// IPv4 addresses are kept as IPv4-mapped IPv6 so both families share one list.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    ranges: Vec<(u128, u128)>,
+}
+
+fn key(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip4) => u128::from(ip4.to_ipv6_mapped()),
+        IpAddr::V6(ip6) => u128::from(ip6),
+    }
+}
+
+// ipfilter.dat pads every octet with zeros, which Ipv4Addr refuses
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    if let Ok(ip) = IpAddr::from_str(s) {
+        return Some(ip);
+    }
+    let mut octets = [0u8; 4];
+    let mut parts = s.split('.');
+    for octet in octets.iter_mut() {
+        *octet = parts.next()?.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+fn parse_range(s: &str) -> Option<(u128, u128)> {
+    let i = s.find('-')?;
+    let (start, end) = (parse_ip(&s[..i])?, parse_ip(&s[i + 1..])?);
+    if (start.is_ipv4() != end.is_ipv4()) || key(start) > key(end) {
+        return None;
+    }
+    Some((key(start), key(end)))
+}
+
+fn parse_cidr(s: &str) -> Option<(u128, u128)> {
+    let (ip, len) = match s.find('/') {
+        Some(i) => (parse_ip(&s[..i])?, s[i + 1..].trim().parse::<u32>().ok()?),
+        None => {
+            let ip = parse_ip(s)?;
+            (ip, if ip.is_ipv4() { 32 } else { 128 })
+        }
+    };
+    // An IPv4 prefix counts from the start of the mapped address
+    let len = match ip {
+        IpAddr::V4(_) if len <= 32 => len + 96,
+        IpAddr::V6(_) if len <= 128 => len,
+        _ => return None,
+    };
+    let host_mask = u128::MAX.checked_shr(len).unwrap_or(0);
+    let start = key(ip) & !host_mask;
+    Some((start, start | host_mask))
+}
+
+// None for a line that blocks nothing, Err for one that can not be read
+fn parse_line(line: &str) -> Result<Option<(u128, u128)>, ()> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+        return Ok(None);
+    }
+
+    if let Some(range) = parse_cidr(line) {
+        return Ok(Some(range));
+    }
+
+    // P2P: description:range, the description may hold colons and commas of its own
+    if let Some(i) = line.rfind(':') {
+        if let Some(range) = parse_range(&line[i + 1..]) {
+            return Ok(Some(range));
+        }
+    }
+
+    // ipfilter.dat: range , level , description
+    if line.contains(',') {
+        let mut fields = line.split(',');
+        let range = fields.next().and_then(parse_range).ok_or(())?;
+        let level = match fields.next() {
+            Some(l) => l.trim().parse::<u32>().map_err(|_| ())?,
+            None => 0,
+        };
+        return Ok(if level < DAT_ALLOW_LEVEL { Some(range) } else { None });
+    }
+
+    // A bare start-end range
+    parse_range(line).map(Some).ok_or(())
+}
+
+fn parse_list(text: &str, path: &Path, ranges: &mut Vec<(u128, u128)>) {
+    for (n, line) in text.lines().enumerate() {
+        match parse_line(line) {
+            Ok(Some(range)) => ranges.push(range),
+            Ok(None) => (),
+            Err(()) => warn!("{} line {}: can not read {:?}", path.display(), n + 1, line),
+        }
+    }
+}
+
+impl Blocklist {
+    /// Read and merge every list, a file that can not be read is an error
+    pub fn load(paths: &[PathBuf]) -> Result<Blocklist, BlocklistError> {
+        let mut ranges = Vec::new();
+        for path in paths {
+            let text = fs::read(path).map_err(|e| BlocklistError(path.clone(), e))?;
+            // Lists in the wild are not always UTF-8, the addresses always are
+            parse_list(&String::from_utf8_lossy(&text), path, &mut ranges);
+        }
+        let blocklist = Blocklist::from_ranges(ranges);
+        if !paths.is_empty() {
+            info!("Blocking {} address ranges", blocklist.ranges.len());
+        }
+        Ok(blocklist)
+    }
+
+    // Overlapping and adjacent ranges become one
+    fn from_ranges(mut ranges: Vec<(u128, u128)>) -> Blocklist {
+        ranges.sort_unstable();
+        let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        Blocklist { ranges: merged }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        if self.ranges.is_empty() {
+            return false;
+        }
+        let ip = key(ip);
+        // The last range starting at or before ip is the only one that can hold it
+        match self.ranges.binary_search_by(|r| r.0.cmp(&ip)) {
+            Ok(_) => true,
+            Err(0) => false,
+            Err(i) => ip <= self.ranges[i - 1].1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::Ipv6Addr;
+    use std::process;
+
+    use super::*;
+
+    fn v4(a: u8, b: u8, c: u8, d: u8) -> u128 {
+        key(IpAddr::V4(Ipv4Addr::new(a, b, c, d)))
+    }
+
+    fn v6(ip: &str) -> u128 {
+        key(ip.parse().unwrap())
+    }
+
+    fn list(text: &str) -> Blocklist {
+        let mut ranges = Vec::new();
+        parse_list(text, Path::new("test.p2p"), &mut ranges);
+        Blocklist::from_ranges(ranges)
+    }
+
+    #[test]
+    fn cidr_lines() {
+        assert_eq!(parse_line("10.0.0.0/8"), Ok(Some((v4(10, 0, 0, 0), v4(10, 255, 255, 255)))));
+        assert_eq!(parse_line(" 192.0.2.1 "), Ok(Some((v4(192, 0, 2, 1), v4(192, 0, 2, 1)))));
+        // Host bits are cleared
+        assert_eq!(parse_line("10.1.2.3/16"), Ok(Some((v4(10, 1, 0, 0), v4(10, 1, 255, 255)))));
+        assert_eq!(
+            parse_line("2001:db8::/32"),
+            Ok(Some((v6("2001:db8::"), v6("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff"))))
+        );
+        assert_eq!(parse_line("0.0.0.0/0"), Ok(Some((v4(0, 0, 0, 0), v4(255, 255, 255, 255)))));
+        assert_eq!(parse_line("10.0.0.0/33"), Err(()));
+        assert_eq!(parse_line("2001:db8::/129"), Err(()));
+        assert_eq!(parse_line("10.0.0/8"), Err(()));
+    }
+
+    #[test]
+    fn ipfilter_dat_lines() {
+        assert_eq!(
+            parse_line("001.002.003.000 - 001.002.003.255 , 000 , Some network"),
+            Ok(Some((v4(1, 2, 3, 0), v4(1, 2, 3, 255))))
+        );
+        // Access levels from 128 up allow rather than block
+        assert_eq!(parse_line("001.002.003.000 - 001.002.003.255 , 200 , Friends"), Ok(None));
+        assert_eq!(parse_line("001.002.003.255 - 001.002.003.000 , 000 , Backwards"), Err(()));
+        assert_eq!(parse_line("001.002.003.000 - 001.002.003.255 , high , Level"), Err(()));
+        assert_eq!(parse_line("001.002.003.256 - 001.002.004.000 , 000 , Octet"), Err(()));
+    }
+
+    #[test]
+    fn p2p_lines() {
+        assert_eq!(
+            parse_line("Some network, Inc: ranges:1.2.3.0-1.2.3.255"),
+            Ok(Some((v4(1, 2, 3, 0), v4(1, 2, 3, 255))))
+        );
+        assert_eq!(parse_line("Some network:1.2.3.0-1.2.3"), Err(()));
+        // Both ends in one family
+        assert_eq!(parse_line("Mixed:1.2.3.0-::ffff"), Err(()));
+    }
+
+    #[test]
+    fn comments_and_bare_ranges() {
+        assert_eq!(parse_line(""), Ok(None));
+        assert_eq!(parse_line("# 10.0.0.0/8"), Ok(None));
+        assert_eq!(parse_line("// 10.0.0.0/8"), Ok(None));
+        assert_eq!(
+            parse_line("2001:db8::1-2001:db8::ff"),
+            Ok(Some((v6("2001:db8::1"), v6("2001:db8::ff"))))
+        );
+        assert_eq!(parse_line("not an address"), Err(()));
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_ranges_merge() {
+        let blocklist = list(
+            "A:1.0.0.5-1.0.0.20\n\
+             1.0.0.0 - 1.0.0.10 , 0 , B\n\
+             1.0.0.21-1.0.0.30\n\
+             1.0.0.32/32\n\
+             garbage\n\
+             2001:db8::/127\n\
+             2001:db8::2\n",
+        );
+        assert_eq!(
+            blocklist.ranges,
+            vec![
+                (v4(1, 0, 0, 0), v4(1, 0, 0, 30)),
+                (v4(1, 0, 0, 32), v4(1, 0, 0, 32)),
+                (v6("2001:db8::"), v6("2001:db8::2")),
+            ]
+        );
+    }
+
+    #[test]
+    fn contains_at_the_edges() {
+        let blocklist = list("10.0.0.0/24\n2001:db8::10-2001:db8::20\n");
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(!blocklist.contains(ip("9.255.255.255")));
+        assert!(blocklist.contains(ip("10.0.0.0")));
+        assert!(blocklist.contains(ip("10.0.0.255")));
+        assert!(!blocklist.contains(ip("10.0.1.0")));
+        // An IPv4 client of a dual-stack socket is the same address
+        assert!(blocklist.contains(IpAddr::V6(Ipv4Addr::new(10, 0, 0, 7).to_ipv6_mapped())));
+        assert!(!blocklist.contains(ip("2001:db8::f")));
+        assert!(blocklist.contains(ip("2001:db8::10")));
+        assert!(blocklist.contains(ip("2001:db8::20")));
+        assert!(!blocklist.contains(ip("2001:db8::21")));
+        assert!(!Blocklist::default().contains(ip("10.0.0.1")));
+        assert!(!blocklist.contains(IpAddr::V6(Ipv6Addr::UNSPECIFIED)));
+    }
+
+    #[test]
+    fn load_reads_every_file() {
+        let dir = env::temp_dir().join(format!("rtracker-blocklist-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.p2p"), dir.join("b.dat"));
+        fs::write(&a, "A:1.0.0.0-1.0.0.255\n").unwrap();
+        fs::write(&b, "002.000.000.000 - 002.000.000.255 , 000 , B\n").unwrap();
+        let blocklist = Blocklist::load(&[a.clone(), b]).unwrap();
+        let missing = Blocklist::load(&[a, dir.join("missing")]);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(blocklist.contains(IpAddr::V4(Ipv4Addr::new(1, 0, 0, 9))));
+        assert!(blocklist.contains(IpAddr::V4(Ipv4Addr::new(2, 0, 0, 9))));
+        assert!(missing.is_err());
+    }
+}
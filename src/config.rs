@@ -13,75 +13,828 @@
 //   You should have received a copy of the GNU Affero General Public License
 //   along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::env;
+use std::ffi::OsStr;
+use std::fmt;
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::thread;
 
 use ini::Ini;
 
-#[derive(Debug)]
+use ratelimit::Limit;
+
+/// The most datagrams one recvmmsg / sendmmsg can carry (UIO_MAXIOV)
+pub const MAX_BATCH_SIZE: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Sqlite,
+    Memory,
+}
+
+/// Which torrents are tracked
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessMode {
+    /// Any torrent
+    Open,
+    /// Only the listed torrents
+    Whitelist,
+    /// Any torrent but the listed ones
+    Blacklist,
+}
+
+#[derive(Debug, Serialize)]
 pub struct ServerConfig {
     pub address: SocketAddr,
+    /// Threads answering UDP packets
+    pub workers: usize,
+    /// UDP sockets sharing address through SO_REUSEPORT (Linux only)
+    pub sockets: usize,
+    /// Datagrams read and answered per syscall with recvmmsg / sendmmsg (Linux only)
+    pub batch_size: usize,
+    /// HTTP announce and scrape, disabled when unset
+    pub http_address: Option<SocketAddr>,
+    /// Prometheus metrics, disabled when unset
+    pub metrics_address: Option<SocketAddr>,
+    /// Admin API, disabled when unset
+    pub admin_address: Option<SocketAddr>,
+    /// Bearer token the admin API wants, never shown by it
+    #[serde(skip)]
+    pub admin_token: Option<String>,
     pub pool_size: usize,
+    /// On-disk SQLite database, in-memory when unset
+    pub db_path: Option<PathBuf>,
+    pub backend: Backend,
+    /// Number of locks the memory backend splits its torrents over
+    pub shards: usize,
+    /// Peers handed out when a client leaves num_want up to us
+    pub default_num_want: usize,
+    /// Most peers handed out, whatever num_want asks for
+    pub max_num_want: usize,
+    /// Seconds clients are told to wait between announces
+    pub announce_interval: u32,
+    /// Seconds clients must wait between announces
+    pub min_interval: u32,
+    /// Seconds without an announce before a peer is dropped from its swarm
+    pub peer_timeout: u32,
+    /// Seconds between looking for timed out peers
+    pub prune_period: u32,
+    pub access_mode: AccessMode,
+    /// Hex info hashes for the access list, the access_list table when unset
+    pub access_file: Option<PathBuf>,
+    /// Directory of .torrent files whose hashes are whitelisted
+    pub torrent_dir: Option<PathBuf>,
+    /// Seconds between checking the access list for changes
+    pub access_reload_period: u32,
+    /// CIDR, ipfilter.dat and P2P lists of banned addresses
+    pub blocklist_files: Vec<PathBuf>,
+    /// Packets accepted from a single address
+    pub ip_limit: Limit,
+    /// Packets accepted from a /24 or /64
+    pub prefix_limit: Limit,
+    /// Packets accepted from everyone together
+    pub global_limit: Limit,
 }
 
-impl ServerConfig {
-    /// Try hard to not fail when reading a config by providing default values
-    pub fn new(path: &String) -> ServerConfig {
-        let mut cfg_path = Path::new("");
-
-        // Given no config option passed
-        if path.is_empty() {
-            // Look in default locations for rtracker.ini
-            let local = Path::new("./rtracker.ini");
-            let home = Path::new("~/.config/rtracker.ini");
-            let system = Path::new("/etc/rtracker.ini");
-            if local.exists() {
-                cfg_path = local;
-            } else if home.exists() {
-                cfg_path = home;
-            } else if system.exists() {
-                cfg_path = system;
+/// Why a config could not be loaded, naming the file and where in it the problem is
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file given with -c does not exist
+    NotFound(PathBuf),
+    /// The file could not be read or is not an ini file
+    Read(PathBuf, String),
+    /// A value that does not parse
+    Value {
+        path:    PathBuf,
+        section: &'static str,
+        key:     String,
+        value:   String,
+        problem: String,
+    },
+    /// Settings that parse but can not work together, `keys` being the ones involved
+    Invalid {
+        path:    PathBuf,
+        keys:    &'static [(&'static str, &'static str)],
+        problem: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::NotFound(ref p) => write!(f, "{}: no such config file", p.display()),
+            ConfigError::Read(ref p, ref e) => write!(f, "{}: {}", p.display(), e),
+            ConfigError::Value {
+                ref path,
+                section,
+                ref key,
+                ref value,
+                ref problem,
+            } => write!(
+                f,
+                "{}: [{}] {} = {:?}: {}",
+                path.display(),
+                section,
+                key,
+                value,
+                problem
+            ),
+            ConfigError::Invalid {
+                ref path,
+                keys,
+                ref problem,
+            } => {
+                // The defaults are used when there is no file at all
+                if !path.as_os_str().is_empty() {
+                    write!(f, "{}: ", path.display())?;
+                }
+                let keys: Vec<String> =
+                    keys.iter().map(|&(s, k)| format!("[{}] {}", s, k)).collect();
+                write!(f, "invalid configuration: {}: {}", keys.join(", "), problem)
             }
-        } else {
-            cfg_path = Path::new(path);
         }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
 
-        if !cfg_path.exists() {
-            cfg_path = Path::new("");
+    fn from_str(s: &str) -> Result<Backend, String> {
+        match s {
+            "sqlite" => Ok(Backend::Sqlite),
+            "memory" => Ok(Backend::Memory),
+            _ => Err(String::from("expected sqlite or memory")),
         }
+    }
+}
 
-        debug!("Loading config: {:?}", cfg_path);
+impl FromStr for AccessMode {
+    type Err = String;
 
-        // Load the ini and grab the server section
-        if cfg_path != Path::new("") {
-            let ini_file: Ini = Ini::load_from_file(cfg_path).unwrap();
-            let server_section = ini_file.section(Some("server")).unwrap();
-            let db_section = ini_file.section(Some("db")).unwrap();
+    fn from_str(s: &str) -> Result<AccessMode, String> {
+        match s {
+            "open" => Ok(AccessMode::Open),
+            "whitelist" => Ok(AccessMode::Whitelist),
+            "blacklist" => Ok(AccessMode::Blacklist),
+            _ => Err(String::from("expected open, whitelist or blacklist")),
+        }
+    }
+}
 
-            // Check for a server address
-            let mut addr = String::new();
-            if server_section.contains_key("address") {
-                addr = server_section.get("address").unwrap().to_string();
-            }
+// Every key read from each section, anything else is most likely a typo
+const KNOWN_KEYS: &[(&str, &[&str])] = &[
+    ("server", &["address", "workers", "sockets", "batch_size"]),
+    ("http", &["address"]),
+    ("metrics", &["address"]),
+    ("admin", &["address", "token"]),
+    ("db", &["backend", "thread_pool_size", "shards", "path"]),
+    (
+        "tracker",
+        &[
+            "default_num_want",
+            "max_num_want",
+            "announce_interval",
+            "min_interval",
+            "peer_timeout",
+            "prune_period",
+        ],
+    ),
+    ("access", &["mode", "file", "torrent_dir", "reload_period"]),
+    ("blocklist", &["files"]),
+    (
+        "ratelimit",
+        &["ip_rate", "ip_burst", "prefix_rate", "prefix_burst", "global_rate", "global_burst"],
+    ),
+];
+
+// One worker per core
+fn default_workers() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+// A leading ~ is the home directory, as a shell would have it
+fn expand_home(path: &str, home: Option<&Path>) -> PathBuf {
+    if path == "~" || path.starts_with("~/") {
+        if let Some(home) = home {
+            return home.join(path[1..].trim_start_matches('/'));
+        }
+    }
+    PathBuf::from(path)
+}
 
-            // Check for db thread pool size option
-            let mut pool_size: usize = 10;
-            if db_section.contains_key("thread_pool_size") {
-                let str_pool_size = db_section.get("thread_pool_size").unwrap();
-                pool_size = str_pool_size.parse::<usize>().unwrap();
+// Where a config is looked for without -c: the working directory, the XDG user and system
+// config directories, then /etc. The roots are $HOME, $XDG_CONFIG_HOME and $XDG_CONFIG_DIRS.
+fn config_candidates(
+    home: Option<&Path>,
+    config_home: Option<&OsStr>,
+    config_dirs: Option<&OsStr>,
+) -> Vec<PathBuf> {
+    let mut candidates = vec![PathBuf::from("rtracker.ini")];
+    // XDG wants relative paths in its variables ignored
+    match config_home.map(Path::new).filter(|p| p.is_absolute()) {
+        Some(dir) => candidates.push(dir.join("rtracker.ini")),
+        None => {
+            if let Some(home) = home {
+                candidates.push(home.join(".config/rtracker.ini"));
             }
+        }
+    }
+    let dirs = config_dirs.unwrap_or_default();
+    let dirs: Vec<PathBuf> = env::split_paths(dirs).filter(|p| p.is_absolute()).collect();
+    if dirs.is_empty() {
+        candidates.push(PathBuf::from("/etc/xdg/rtracker.ini"));
+    }
+    candidates.extend(dirs.into_iter().map(|d| d.join("rtracker.ini")));
+    candidates.push(PathBuf::from("/etc/rtracker.ini"));
+    candidates
+}
+
+// Reads values out of a loaded ini, errors name the file, section and key
+struct Reader<'a> {
+    path: &'a Path,
+    ini:  &'a Ini,
+    /// What a leading ~ in a path stands for
+    home: Option<&'a Path>,
+}
 
-            // Return the object
-            ServerConfig {
-                address: SocketAddr::from_str(addr.as_str()).unwrap(),
-                pool_size,
+impl<'a> Reader<'a> {
+    fn get(&self, section: &'static str, key: &str) -> Option<&'a str> {
+        self.ini.section(Some(section)).and_then(|s| s.get(key))
+    }
+
+    fn error(&self, section: &'static str, key: &str, value: &str, problem: String) -> ConfigError {
+        ConfigError::Value {
+            path: self.path.to_path_buf(),
+            section,
+            key: key.to_string(),
+            value: value.to_string(),
+            problem,
+        }
+    }
+
+    /// Overwrites the default in `slot` when the key is set
+    fn parse<T>(&self, section: &'static str, key: &str, slot: &mut T) -> Result<(), ConfigError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        if let Some(value) = self.get(section, key) {
+            *slot = value
+                .parse()
+                .map_err(|e: T::Err| self.error(section, key, value, e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// An optional value, unset when the key is missing or empty
+    fn optional<T>(&self, section: &'static str, key: &str) -> Result<Option<T>, ConfigError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        match self.get(section, key) {
+            Some(value) if !value.is_empty() => value
+                .parse()
+                .map(Some)
+                .map_err(|e: T::Err| self.error(section, key, value, e.to_string())),
+            _ => Ok(None),
+        }
+    }
+
+    fn path(&self, section: &'static str, key: &str) -> Option<PathBuf> {
+        self.get(section, key)
+            .filter(|p| !p.is_empty())
+            .map(|p| expand_home(p, self.home))
+    }
+
+    // Unknown keys are ignored, but said so
+    fn unknown(&self) -> Vec<String> {
+        let mut unknown = Vec::new();
+        for (section, properties) in self.ini.iter() {
+            let name = section.unwrap_or("");
+            match KNOWN_KEYS.iter().find(|&&(s, _)| s == name) {
+                Some(&(_, keys)) => {
+                    for (key, _) in properties.iter().filter(|&(k, _)| !keys.contains(&k)) {
+                        unknown.push(format!("unknown key [{}] {}", name, key));
+                    }
+                }
+                // Keys above the first section
+                None if section.is_none() && properties.is_empty() => {}
+                None if section.is_none() => {
+                    unknown.push(String::from("keys outside of any section"));
+                }
+                None => unknown.push(format!("unknown section [{}]", name)),
             }
+        }
+        unknown
+    }
+}
+
+impl Default for ServerConfig {
+    /// The values documented in rtracker.ini.example
+    fn default() -> ServerConfig {
+        ServerConfig {
+            address: SocketAddr::from(([127, 0, 0, 1], 6969)),
+            workers: default_workers(),
+            sockets: 1,
+            batch_size: 1,
+            http_address: None,
+            metrics_address: None,
+            admin_address: None,
+            admin_token: None,
+            pool_size: 10,
+            db_path: None,
+            backend: Backend::Sqlite,
+            shards: 64,
+            default_num_want: 50,
+            max_num_want: 200,
+            announce_interval: 1800,
+            min_interval: 900,
+            peer_timeout: 2700,
+            prune_period: 60,
+            access_mode: AccessMode::Open,
+            access_file: None,
+            torrent_dir: None,
+            access_reload_period: 60,
+            blocklist_files: Vec::new(),
+            ip_limit: Limit {
+                rate: 20,
+                burst: 200,
+            },
+            prefix_limit: Limit {
+                rate: 200,
+                burst: 2000,
+            },
+            global_limit: Limit { rate: 0, burst: 0 },
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Load and validate the config at path, or the first one found in the default locations
+    /// when path is empty. Missing sections and keys keep their defaults.
+    pub fn new(path: &str) -> Result<ServerConfig, ConfigError> {
+        let home = env::var_os("HOME").map(PathBuf::from);
+        let home = home.as_deref();
+        let cfg_path = if path.is_empty() {
+            let config_home = env::var_os("XDG_CONFIG_HOME");
+            let config_dirs = env::var_os("XDG_CONFIG_DIRS");
+            config_candidates(home, config_home.as_deref(), config_dirs.as_deref())
+                .into_iter()
+                .find(|p| p.exists())
         } else {
-            ServerConfig {
-                address: SocketAddr::from_str("127.0.0.1:6969").unwrap(),
-                pool_size: 10,
+            let given = expand_home(path, home);
+            if !given.exists() {
+                return Err(ConfigError::NotFound(given));
+            }
+            Some(given)
+        };
+
+        let config = match cfg_path {
+            Some(ref p) => {
+                info!("Loading config: {}", p.display());
+                let ini = Ini::load_from_file(p)
+                    .map_err(|e| ConfigError::Read(p.clone(), e.to_string()))?;
+                ServerConfig::from_ini(&Reader {
+                    path: p,
+                    ini: &ini,
+                    home,
+                })?
+            }
+            None => {
+                info!("No config file found, using the defaults");
+                ServerConfig::default()
             }
+        };
+        config.validate(&cfg_path.unwrap_or_default())?;
+        Ok(config)
+    }
+
+    fn from_ini(ini: &Reader) -> Result<ServerConfig, ConfigError> {
+        for unknown in ini.unknown() {
+            warn!("{}: {}", ini.path.display(), unknown);
         }
+        let mut cfg = ServerConfig::default();
+
+        ini.parse("server", "address", &mut cfg.address)?;
+        ini.parse("server", "workers", &mut cfg.workers)?;
+        ini.parse("server", "sockets", &mut cfg.sockets)?;
+        ini.parse("server", "batch_size", &mut cfg.batch_size)?;
+
+        cfg.http_address = ini.optional("http", "address")?;
+        cfg.metrics_address = ini.optional("metrics", "address")?;
+        cfg.admin_address = ini.optional("admin", "address")?;
+        cfg.admin_token = ini.get("admin", "token").map(String::from);
+
+        ini.parse("db", "backend", &mut cfg.backend)?;
+        ini.parse("db", "thread_pool_size", &mut cfg.pool_size)?;
+        ini.parse("db", "shards", &mut cfg.shards)?;
+        // An empty path keeps the database in memory
+        cfg.db_path = ini.path("db", "path");
+
+        ini.parse("tracker", "default_num_want", &mut cfg.default_num_want)?;
+        ini.parse("tracker", "max_num_want", &mut cfg.max_num_want)?;
+        ini.parse("tracker", "announce_interval", &mut cfg.announce_interval)?;
+        ini.parse("tracker", "min_interval", &mut cfg.min_interval)?;
+        ini.parse("tracker", "peer_timeout", &mut cfg.peer_timeout)?;
+        ini.parse("tracker", "prune_period", &mut cfg.prune_period)?;
+
+        ini.parse("access", "mode", &mut cfg.access_mode)?;
+        cfg.access_file = ini.path("access", "file");
+        cfg.torrent_dir = ini.path("access", "torrent_dir");
+        ini.parse("access", "reload_period", &mut cfg.access_reload_period)?;
+
+        // A comma separated list of files
+        if let Some(files) = ini.get("blocklist", "files") {
+            cfg.blocklist_files = files
+                .split(',')
+                .map(|f| f.trim())
+                .filter(|f| !f.is_empty())
+                .map(|f| expand_home(f, ini.home))
+                .collect();
+        }
+
+        ini.parse("ratelimit", "ip_rate", &mut cfg.ip_limit.rate)?;
+        ini.parse("ratelimit", "ip_burst", &mut cfg.ip_limit.burst)?;
+        ini.parse("ratelimit", "prefix_rate", &mut cfg.prefix_limit.rate)?;
+        ini.parse("ratelimit", "prefix_burst", &mut cfg.prefix_limit.burst)?;
+        ini.parse("ratelimit", "global_rate", &mut cfg.global_limit.rate)?;
+        ini.parse("ratelimit", "global_burst", &mut cfg.global_limit.burst)?;
+
+        Ok(cfg)
+    }
+
+    /// Reject settings that can not work together, `path` being where they were read from
+    pub fn validate(&self, path: &Path) -> Result<(), ConfigError> {
+        let invalid = |keys, problem: String| {
+            Err(ConfigError::Invalid {
+                path: path.to_path_buf(),
+                keys,
+                problem,
+            })
+        };
+        if self.workers == 0 {
+            return invalid(&[("server", "workers")], String::from("must be above 0"));
+        }
+        // A socket nobody reads would swallow its share of the clients
+        if self.sockets == 0 || self.sockets > self.workers {
+            return invalid(
+                &[("server", "sockets"), ("server", "workers")],
+                format!(
+                    "sockets ({}) must be between 1 and workers ({})",
+                    self.sockets, self.workers
+                ),
+            );
+        }
+        let listeners = [self.http_address, self.metrics_address, self.admin_address];
+        for (i, a) in listeners.iter().enumerate() {
+            if a.is_some() && listeners[i + 1..].contains(a) {
+                return invalid(
+                    &[("http", "address"), ("metrics", "address"), ("admin", "address")],
+                    String::from("need addresses of their own"),
+                );
+            }
+        }
+        // An empty or missing token would leave the API open to anyone who can reach it
+        let no_token = self.admin_token.as_ref().is_none_or(|t| t.is_empty());
+        if self.admin_address.is_some() && no_token {
+            return invalid(
+                &[("admin", "address"), ("admin", "token")],
+                String::from("the admin API needs a token"),
+            );
+        }
+        if self.batch_size == 0 || self.batch_size > MAX_BATCH_SIZE {
+            return invalid(
+                &[("server", "batch_size")],
+                format!("{} is not between 1 and {}", self.batch_size, MAX_BATCH_SIZE),
+            );
+        }
+        if self.announce_interval == 0 {
+            return invalid(&[("tracker", "announce_interval")], String::from("must be above 0"));
+        }
+        // The UDP announce reply carries the interval as a signed 32 bit integer
+        if self.announce_interval > i32::MAX as u32 {
+            return invalid(
+                &[("tracker", "announce_interval")],
+                format!("must be at most {}", i32::MAX),
+            );
+        }
+        if self.min_interval == 0 {
+            return invalid(&[("tracker", "min_interval")], String::from("must be above 0"));
+        }
+        if self.prune_period == 0 {
+            return invalid(&[("tracker", "prune_period")], String::from("must be above 0"));
+        }
+        if self.min_interval > self.announce_interval {
+            return invalid(
+                &[("tracker", "min_interval"), ("tracker", "announce_interval")],
+                format!(
+                    "min_interval ({}) is longer than announce_interval ({})",
+                    self.min_interval, self.announce_interval
+                ),
+            );
+        }
+        // Peers would be forgotten before they are due to announce again
+        if self.peer_timeout <= self.announce_interval {
+            return invalid(
+                &[("tracker", "peer_timeout"), ("tracker", "announce_interval")],
+                format!(
+                    "peer_timeout ({}) must be longer than announce_interval ({})",
+                    self.peer_timeout, self.announce_interval
+                ),
+            );
+        }
+        if self.access_reload_period == 0 {
+            return invalid(&[("access", "reload_period")], String::from("must be above 0"));
+        }
+        if self.torrent_dir.is_some() && self.access_mode != AccessMode::Whitelist {
+            return invalid(
+                &[("access", "torrent_dir"), ("access", "mode")],
+                String::from("torrent_dir only applies to the whitelist mode"),
+            );
+        }
+        // The access_list table lives in the SQLite database
+        if self.access_mode != AccessMode::Open
+            && self.access_file.is_none()
+            && self.torrent_dir.is_none()
+            && self.backend != Backend::Sqlite
+        {
+            return invalid(
+                &[
+                    ("access", "mode"),
+                    ("access", "file"),
+                    ("access", "torrent_dir"),
+                    ("db", "backend"),
+                ],
+                String::from("a list needs a file or torrent_dir with the memory backend"),
+            );
+        }
+        // A limited bucket that holds less than one packet lets nothing through
+        let limits: [(&'static [(&str, &str)], Limit); 3] = [
+            (&[("ratelimit", "ip_burst"), ("ratelimit", "ip_rate")], self.ip_limit),
+            (&[("ratelimit", "prefix_burst"), ("ratelimit", "prefix_rate")], self.prefix_limit),
+            (&[("ratelimit", "global_burst"), ("ratelimit", "global_rate")], self.global_limit),
+        ];
+        for &(keys, limit) in &limits {
+            if limit.rate > 0 && limit.burst == 0 {
+                return invalid(keys, String::from("the burst must be above 0 when a rate is set"));
+            }
+        }
+        if self.default_num_want > self.max_num_want {
+            return invalid(
+                &[("tracker", "default_num_want"), ("tracker", "max_num_want")],
+                format!(
+                    "default_num_want ({}) is larger than max_num_want ({})",
+                    self.default_num_want, self.max_num_want
+                ),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process;
+
+    use super::*;
+
+    const HOME: &str = "/home/tracker";
+
+    fn load(text: &str) -> Result<ServerConfig, ConfigError> {
+        let ini = Ini::load_from_str(text).unwrap();
+        let path = Path::new("test.ini");
+        let home = Some(Path::new(HOME));
+        let config = ServerConfig::from_ini(&Reader {
+            path,
+            ini: &ini,
+            home,
+        })?;
+        config.validate(path)?;
+        Ok(config)
+    }
+
+    fn unknown(text: &str) -> Vec<String> {
+        let ini = Ini::load_from_str(text).unwrap();
+        Reader {
+            path: Path::new("test.ini"),
+            ini:  &ini,
+            home: None,
+        }
+        .unknown()
+    }
+
+    // The [section] key of a value that did not parse
+    fn bad_value(text: &str) -> (&'static str, String) {
+        match load(text) {
+            Err(ConfigError::Value { section, key, .. }) => (section, key),
+            other => panic!("expected a value error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn missing_file() {
+        let name = format!("rtracker-config-test-missing-{}.ini", process::id());
+        let path = env::temp_dir().join(name);
+        match ServerConfig::new(path.to_str().unwrap()) {
+            Err(ConfigError::NotFound(p)) => assert_eq!(p, path),
+            other => panic!("expected NotFound, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn missing_sections_keep_defaults() {
+        let name = format!("rtracker-config-test-no-db-{}.ini", process::id());
+        let path = env::temp_dir().join(name);
+        fs::write(&path, "[server]\naddress = 127.0.0.1:7000\n").unwrap();
+        let config = ServerConfig::new(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+        assert_eq!(config.address, SocketAddr::from(([127, 0, 0, 1], 7000)));
+        assert_eq!(config.backend, Backend::Sqlite);
+        assert_eq!(config.pool_size, 10);
+        assert_eq!(config.db_path, None);
+        assert_eq!(config.announce_interval, 1800);
+    }
+
+    #[test]
+    fn example_config_loads() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/rtracker.ini.example");
+        assert!(ServerConfig::new(path).is_ok());
+    }
+
+    #[test]
+    fn empty_or_invalid_addresses() {
+        assert_eq!(bad_value("[server]\naddress =\n"), ("server", String::from("address")));
+        assert_eq!(bad_value("[server]\naddress = nowhere\n"), ("server", String::from("address")));
+        assert_eq!(bad_value("[http]\naddress = 1.2.3.4\n"), ("http", String::from("address")));
+        assert_eq!(bad_value("[db]\nbackend = mysql\n"), ("db", String::from("backend")));
+        // An empty optional address leaves the listener off
+        assert_eq!(load("[metrics]\naddress =\n").unwrap().metrics_address, None);
+    }
+
+    #[test]
+    fn value_errors_name_the_key() {
+        let e = load("[tracker]\nmin_interval = soon\n").err().unwrap();
+        assert!(e.to_string().starts_with("test.ini: [tracker] min_interval = \"soon\": "));
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_not_fatal() {
+        let text = "stray = 1\n[server]\nadress = 127.0.0.1:1\n[serve]\nworkers = 2\n";
+        assert_eq!(
+            unknown(text),
+            vec![
+                "keys outside of any section",
+                "unknown key [server] adress",
+                "unknown section [serve]",
+            ]
+        );
+        assert!(load(text).is_ok());
+        assert!(unknown("[server]\naddress = 127.0.0.1:1\n[db]\nshards = 8\n").is_empty());
+    }
+
+    #[test]
+    fn expands_home() {
+        let expand = |path: &str| expand_home(path, Some(Path::new(HOME)));
+        assert_eq!(expand("~"), PathBuf::from("/home/tracker"));
+        assert_eq!(expand("~/db/r.db"), PathBuf::from("/home/tracker/db/r.db"));
+        // Another user's home and a ~ further in are left alone, as is all of it without a home
+        assert_eq!(expand("~other/r.db"), PathBuf::from("~other/r.db"));
+        assert_eq!(expand("db/~/r.db"), PathBuf::from("db/~/r.db"));
+        assert_eq!(expand_home("~/r.db", None), PathBuf::from("~/r.db"));
+        let config = load("[db]\npath = ~/r.db\n[blocklist]\nfiles = ~/a.p2p, /b.dat\n").unwrap();
+        assert_eq!(config.db_path, Some(PathBuf::from("/home/tracker/r.db")));
+        assert_eq!(
+            config.blocklist_files,
+            vec![PathBuf::from("/home/tracker/a.p2p"), PathBuf::from("/b.dat")]
+        );
+    }
+
+    fn candidates(config_home: &str, config_dirs: &str) -> Vec<PathBuf> {
+        let home = Some(Path::new(HOME));
+        config_candidates(home, Some(config_home.as_ref()), Some(config_dirs.as_ref()))
+    }
+
+    #[test]
+    fn xdg_search_order() {
+        let paths = |list: &[&str]| list.iter().map(PathBuf::from).collect::<Vec<_>>();
+        assert_eq!(
+            candidates("/cfg", "/xdg/a:/xdg/b"),
+            paths(&[
+                "rtracker.ini",
+                "/cfg/rtracker.ini",
+                "/xdg/a/rtracker.ini",
+                "/xdg/b/rtracker.ini",
+                "/etc/rtracker.ini",
+            ])
+        );
+        // Relative paths are ignored, falling back to the defaults
+        assert_eq!(
+            candidates("cfg", "xdg/a"),
+            paths(&[
+                "rtracker.ini",
+                "/home/tracker/.config/rtracker.ini",
+                "/etc/xdg/rtracker.ini",
+                "/etc/rtracker.ini",
+            ])
+        );
+        assert_eq!(
+            candidates("", "xdg/a:/xdg/b"),
+            paths(&[
+                "rtracker.ini",
+                "/home/tracker/.config/rtracker.ini",
+                "/xdg/b/rtracker.ini",
+                "/etc/rtracker.ini",
+            ])
+        );
+        // Nothing set, not even HOME
+        assert_eq!(
+            config_candidates(None, None, None),
+            paths(&["rtracker.ini", "/etc/xdg/rtracker.ini", "/etc/rtracker.ini"])
+        );
+    }
+
+    // The keys validate() blames for `text`
+    fn invalid(text: &str) -> &'static [(&'static str, &'static str)] {
+        match load(text) {
+            Err(ConfigError::Invalid { keys, .. }) => keys,
+            other => panic!("{:?} is valid: {:?}", text, other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn validate_rules() {
+        let server = "server";
+        assert_eq!(invalid("[server]\nworkers = 0\n"), &[(server, "workers")]);
+        assert_eq!(
+            invalid("[server]\nworkers = 2\nsockets = 3\n"),
+            &[(server, "sockets"), (server, "workers")]
+        );
+        assert_eq!(invalid("[server]\nsockets = 0\n")[0], (server, "sockets"));
+        assert_eq!(
+            invalid("[http]\naddress = 127.0.0.1:80\n[metrics]\naddress = 127.0.0.1:80\n").len(),
+            3
+        );
+        assert_eq!(
+            invalid("[admin]\naddress = 127.0.0.1:81\ntoken =\n"),
+            &[("admin", "address"), ("admin", "token")]
+        );
+        assert_eq!(invalid("[server]\nbatch_size = 0\n"), &[(server, "batch_size")]);
+        assert_eq!(invalid("[server]\nbatch_size = 1025\n"), &[(server, "batch_size")]);
+
+        let tracker = "tracker";
+        assert_eq!(
+            invalid("[tracker]\nannounce_interval = 0\n"),
+            &[(tracker, "announce_interval")]
+        );
+        assert_eq!(
+            invalid("[tracker]\nannounce_interval = 2147483648\npeer_timeout = 4294967295\n"),
+            &[(tracker, "announce_interval")]
+        );
+        assert_eq!(invalid("[tracker]\nmin_interval = 0\n"), &[(tracker, "min_interval")]);
+        assert_eq!(invalid("[tracker]\nprune_period = 0\n"), &[(tracker, "prune_period")]);
+        assert_eq!(
+            invalid("[tracker]\nmin_interval = 1801\n"),
+            &[(tracker, "min_interval"), (tracker, "announce_interval")]
+        );
+        assert_eq!(
+            invalid("[tracker]\npeer_timeout = 1800\n"),
+            &[(tracker, "peer_timeout"), (tracker, "announce_interval")]
+        );
+        assert_eq!(
+            invalid("[tracker]\ndefault_num_want = 201\n"),
+            &[(tracker, "default_num_want"), (tracker, "max_num_want")]
+        );
+
+        assert_eq!(invalid("[access]\nreload_period = 0\n"), &[("access", "reload_period")]);
+        assert_eq!(
+            invalid("[access]\nmode = blacklist\ntorrent_dir = /t\n"),
+            &[("access", "torrent_dir"), ("access", "mode")]
+        );
+        assert_eq!(invalid("[access]\nmode = whitelist\n[db]\nbackend = memory\n").len(), 4);
+        assert!(load("[access]\nmode = whitelist\nfile = /w\n[db]\nbackend = memory\n").is_ok());
+
+        for name in &["ip", "prefix", "global"] {
+            let text = format!("[ratelimit]\n{}_rate = 5\n{}_burst = 0\n", name, name);
+            assert_eq!(invalid(&text)[0].0, "ratelimit");
+            assert!(invalid(&text)[0].1.starts_with(name));
+        }
+        assert!(load("[ratelimit]\nglobal_rate = 0\nglobal_burst = 0\n").is_ok());
+    }
+
+    #[test]
+    fn invalid_errors_name_the_keys() {
+        let e = load("[tracker]\nmin_interval = 1801\n").err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "test.ini: invalid configuration: [tracker] min_interval, [tracker] \
+             announce_interval: min_interval (1801) is longer than announce_interval (1800)"
+        );
     }
 }
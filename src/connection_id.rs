@@ -0,0 +1,131 @@
+//  rtracker: bittorrent tracker
+//  Copyright (C) 2019  Justin Noah <justinnoah@gmail.com>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License.
+//
+//  This program is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//   GNU Affero General Public License for more details.
+//
+//   You should have received a copy of the GNU Affero General Public License
+//   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::net::IpAddr;
+
+use chrono::prelude::Utc;
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, Rng};
+use sha2::Sha256;
+
+// Magic number according to
+// http://www.rasterbar.com/products/libtorrent/udp_tracker_protocol.html
+pub const PROTOCOL_ID: i64 = 0x41727101980;
+
+// BEP 15: a connection ID may be used for up to two minutes after it was issued
+const MAX_AGE: i64 = 120;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Issues and validates connection IDs without keeping a table of them.
+///
+/// An ID is the 32bit issue time followed by the first 32bits of an HMAC over the client's IP
+/// and that same time, keyed with a secret generated at startup.
+pub struct ConnectionIds {
+    secret: [u8; 32],
+}
+
+impl ConnectionIds {
+    pub fn new() -> ConnectionIds {
+        let mut secret = [0u8; 32];
+        thread_rng().fill(&mut secret);
+        ConnectionIds { secret }
+    }
+
+    fn mac(&self, ip: IpAddr, issued: u32) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).unwrap();
+        match ip {
+            IpAddr::V4(x) => mac.update(&x.octets()),
+            IpAddr::V6(y) => mac.update(&y.octets()),
+        }
+        mac.update(&issued.to_be_bytes());
+        mac
+    }
+
+    /// Generate a connection ID for a client at `ip`
+    pub fn issue(&self, ip: IpAddr) -> i64 {
+        self.issue_at(ip, Utc::now().timestamp() as u32)
+    }
+
+    fn issue_at(&self, ip: IpAddr, issued: u32) -> i64 {
+        let tag = self.mac(ip, issued).finalize().into_bytes();
+
+        let mut id = [0u8; 8];
+        id[..4].copy_from_slice(&issued.to_be_bytes());
+        id[4..].copy_from_slice(&tag[..4]);
+        i64::from_be_bytes(id)
+    }
+
+    /// Check that `id` was issued by this tracker to `ip` within the last two minutes
+    pub fn validate(&self, id: i64, ip: IpAddr) -> bool {
+        let id = id.to_be_bytes();
+        let mut issued = [0u8; 4];
+        issued.copy_from_slice(&id[..4]);
+        let issued = u32::from_be_bytes(issued);
+
+        // Compare in u32 space so the check survives the timestamp wrapping
+        let age = (Utc::now().timestamp() as u32).wrapping_sub(issued) as i64;
+        if age > MAX_AGE {
+            debug!("Connection ID expired {} seconds ago", age - MAX_AGE);
+            return false;
+        }
+
+        self.mac(ip, issued).verify_truncated_left(&id[4..]).is_ok()
+    }
+}
+
+impl Default for ConnectionIds {
+    fn default() -> ConnectionIds {
+        ConnectionIds::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> u32 {
+        Utc::now().timestamp() as u32
+    }
+
+    #[test]
+    fn issued_id_checks_out() {
+        let ids = ConnectionIds::new();
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        assert!(ids.validate(ids.issue(ip), ip));
+        assert!(ids.validate(ids.issue_at(ip, now() - 100), ip));
+
+        let ip6 = IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1]);
+        assert!(ids.validate(ids.issue(ip6), ip6));
+    }
+
+    #[test]
+    fn expired_id_is_refused() {
+        let ids = ConnectionIds::new();
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        assert!(!ids.validate(ids.issue_at(ip, now() - 200), ip));
+        // Nor is one from the future
+        assert!(!ids.validate(ids.issue_at(ip, now() + 200), ip));
+    }
+
+    #[test]
+    fn id_only_checks_out_for_its_address() {
+        let ids = ConnectionIds::new();
+        let id = ids.issue(IpAddr::from([10, 0, 0, 1]));
+        assert!(!ids.validate(id, IpAddr::from([10, 0, 0, 2])));
+        // Nor with another tracker's secret
+        assert!(!ConnectionIds::new().validate(id, IpAddr::from([10, 0, 0, 1])));
+    }
+}
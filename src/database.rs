@@ -13,56 +13,685 @@
 //   You should have received a copy of the GNU Affero General Public License
 //   along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use r2d2;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::result;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use r2d2::event::{CheckoutEvent, HandleEvent};
+use r2d2::{Pool, PooledConnection};
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::*;
 
-pub type PoolCon = r2d2::PooledConnection<SqliteConnectionManager>;
+use packet_data_types::{Event, ScrapeStats};
+use stats::Histogram;
+use storage::{Announce, Family, InfoHash, Peer, PeerId, PeerStore, StoreError, Totals};
 
-pub fn db_connection_pool(pool_size: usize) -> r2d2::Pool<SqliteConnectionManager> {
-    let flags = {
-        OpenFlags::SQLITE_OPEN_READ_WRITE
-            | OpenFlags::SQLITE_OPEN_CREATE
-            | OpenFlags::SQLITE_OPEN_MEMORY
-            | OpenFlags::SQLITE_OPEN_FULL_MUTEX
-            | OpenFlags::SQLITE_OPEN_URI
-            | OpenFlags::SQLITE_OPEN_SHARED_CACHE
-    };
+// Applied to every on-disk connection as the pool opens it
+const DISK_PRAGMAS: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+    PRAGMA busy_timeout = 5000;
+    PRAGMA temp_store = MEMORY;";
+
+// Times every connection checkout for the metrics
+#[derive(Debug)]
+struct PoolWait(Arc<Histogram>);
 
+impl HandleEvent for PoolWait {
+    fn handle_checkout(&self, event: CheckoutEvent) {
+        self.0.observe(event.duration());
+    }
+}
+
+/// Without a path the database lives in memory and is lost on exit
+pub fn db_connection_pool(
+    pool_size: usize,
+    path: Option<&Path>,
+    wait: Arc<Histogram>,
+) -> result::Result<DbPool, StoreError> {
+    let pool = open_pool(pool_size, path, wait.clone())?;
+    Ok(DbPool {
+        pool: Arc::new(RwLock::new(pool)),
+        path: path.map(Path::to_path_buf),
+        wait,
+    })
+}
+
+fn open_pool(
+    pool_size: usize,
+    path: Option<&Path>,
+    wait: Arc<Histogram>,
+) -> result::Result<Pool<SqliteConnectionManager>, StoreError> {
     debug!("{:?} threads available", pool_size);
 
-    let manager =
-        SqliteConnectionManager::file("file:blah?mode=memory&cache=shared").with_flags(flags);
+    let manager = match path {
+        Some(p) => {
+            info!("Using database: {}", p.display());
+            let flags = {
+                OpenFlags::SQLITE_OPEN_READ_WRITE
+                    | OpenFlags::SQLITE_OPEN_CREATE
+                    | OpenFlags::SQLITE_OPEN_FULL_MUTEX
+            };
+            SqliteConnectionManager::file(p)
+                .with_flags(flags)
+                .with_init(|c| c.execute_batch(DISK_PRAGMAS))
+        }
+        None => {
+            info!("Using an in-memory database");
+            let flags = {
+                OpenFlags::SQLITE_OPEN_READ_WRITE
+                    | OpenFlags::SQLITE_OPEN_CREATE
+                    | OpenFlags::SQLITE_OPEN_MEMORY
+                    | OpenFlags::SQLITE_OPEN_FULL_MUTEX
+                    | OpenFlags::SQLITE_OPEN_URI
+                    | OpenFlags::SQLITE_OPEN_SHARED_CACHE
+            };
+            SqliteConnectionManager::file("file:blah?mode=memory&cache=shared").with_flags(flags)
+        }
+    };
 
-    r2d2::Pool::builder()
+    Ok(Pool::builder()
         .max_size(pool_size as u32)
-        .build(manager)
-        .unwrap()
+        .event_handler(Box::new(PoolWait(wait)))
+        .build(manager)?)
 }
 
-// Initialize the database
-pub fn db_init(conn: PoolCon) {
-    conn.execute(
+/// The connection pool shared by the store and the access list table, a reload may replace it
+/// with one of another size
+#[derive(Clone)]
+pub struct DbPool {
+    pool: Arc<RwLock<Pool<SqliteConnectionManager>>>,
+    path: Option<PathBuf>,
+    wait: Arc<Histogram>,
+}
+
+impl DbPool {
+    pub fn get(&self) -> result::Result<PooledConnection<SqliteConnectionManager>, r2d2::Error> {
+        // A checkout keeps the pool it came from, a resize does not wait for it
+        let pool = self.pool.read().unwrap_or_else(|e| e.into_inner()).clone();
+        pool.get()
+    }
+
+    pub fn size(&self) -> usize {
+        self.pool.read().unwrap_or_else(|e| e.into_inner()).max_size() as usize
+    }
+
+    /// Replace the pool with one of `pool_size` connections. The new pool opens all of them
+    /// before the old one closes any, so an in-memory database outlives the swap.
+    pub fn resize(&self, pool_size: usize) -> result::Result<(), StoreError> {
+        let pool = open_pool(pool_size, self.path.as_deref(), self.wait.clone())?;
+        *self.pool.write().unwrap_or_else(|e| e.into_inner()) = pool;
+        Ok(())
+    }
+}
+
+// Bumped with every change to the tables below, kept in PRAGMA user_version
+const SCHEMA_VERSION: i32 = 2;
+
+// MIGRATIONS[n] takes a database from version n to n + 1. Databases from before the version
+// was kept are at 0.
+const MIGRATIONS: &[&str] = &[
+    // Peers are keyed by address instead of address and peer_id, the newest announce of an
+    // address wins
+    "ALTER TABLE torrent RENAME TO torrent_old;
+    CREATE TABLE torrent (
+        info_hash   TEXT,
+        ip          TEXT,
+        port        INTEGER,
+        family      INTEGER,
+        peer_id     TEXT,
+        remaining   INTEGER,
+        last_active INTEGER,
+        started     INTEGER,
+        PRIMARY KEY (info_hash, ip, port)
+    );
+    INSERT OR REPLACE INTO torrent
+        (info_hash, ip, port, peer_id, remaining, last_active, started)
+    SELECT info_hash, ip, port, peer_id, remaining, last_active, started
+    FROM torrent_old ORDER BY last_active;
+    DROP TABLE torrent_old;",
+    // Peers are handed out one address family at a time, those kept before the family was
+    // recorded get it from their address
+    "UPDATE torrent SET family = CASE WHEN instr(ip, ':') > 0 THEN 6 ELSE 4 END
+    WHERE family IS NULL;",
+];
+
+// Initialize the database, bringing the tables of an older one up to date
+pub fn db_init(conn: &mut Connection) -> result::Result<(), StoreError> {
+    let tx = conn.transaction()?;
+    let mut version: i32 = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > SCHEMA_VERSION {
+        return Err(StoreError::Schema(format!(
+            "the database is at schema version {}, newer than this rtracker knows ({})",
+            version, SCHEMA_VERSION
+        )));
+    }
+    let fresh: bool = tx.query_row(
+        "SELECT NOT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'torrent')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !fresh {
+        while version < SCHEMA_VERSION {
+            info!("Upgrading the database to schema version {}", version + 1);
+            tx.execute_batch(MIGRATIONS[version as usize])?;
+            version += 1;
+        }
+    }
+    tx.execute_batch(
         "
         CREATE TABLE IF NOT EXISTS torrent (
             info_hash   TEXT,
             ip          TEXT,
             port        INTEGER,
+            -- 4 or 6, so a swarm can be handed out one address family at a time
+            family      INTEGER,
             peer_id     TEXT,
             remaining   INTEGER,
             last_active INTEGER,
-            PRIMARY KEY (info_hash, ip, port, peer_id)
+            started     INTEGER,
+            -- A peer is its address, a client restarting with a new peer_id replaces itself
+            PRIMARY KEY (info_hash, ip, port)
+        );
+        -- Survives pruning, unlike the peers in torrent
+        CREATE TABLE IF NOT EXISTS torrent_stats (
+            info_hash   TEXT PRIMARY KEY,
+            completed   INTEGER NOT NULL DEFAULT 0
+        );
+        -- Names of the torrents loaded from .torrent files, keyed like torrent
+        CREATE TABLE IF NOT EXISTS torrent_name (
+            info_hash   TEXT PRIMARY KEY,
+            name        TEXT NOT NULL
+        );
+        -- Whitelist or blacklist, as hex so it can be kept by hand
+        CREATE TABLE IF NOT EXISTS access_list (
+            info_hash   TEXT PRIMARY KEY
         );",
-        params![],
-    )
-    .unwrap();
+    )?;
+    tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    tx.commit()?;
+    Ok(())
 }
 
-pub fn db_prune(conn: PoolCon) {
+pub fn db_prune(conn: &Connection, timeout: i64) -> Result<usize> {
     conn.execute(
         "DELETE FROM torrent
-        WHERE (strftime('%s','now') - last_active) > 300;",
-        params![],
+        WHERE (strftime('%s','now') - last_active) > ?;",
+        params![timeout],
     )
-    .unwrap();
+}
+
+pub fn db_access_list(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT info_hash FROM access_list")?;
+    let rows = stmt.query_map([], |row| row.get(0))?;
+    rows.collect()
+}
+
+pub fn db_access_add(conn: &Connection, hex: &str) -> Result<usize> {
+    conn.execute("INSERT OR IGNORE INTO access_list (info_hash) VALUES (?)", [hex])
+}
+
+// Hashes kept by hand may be in any case or padded
+pub fn db_access_remove(conn: &Connection, hex: &str) -> Result<usize> {
+    conn.execute(
+        "DELETE FROM access_list WHERE lower(trim(info_hash)) = lower(?)",
+        [hex],
+    )
+}
+
+fn family_column(family: Family) -> i32 {
+    match family {
+        Family::V4 => 4,
+        Family::V6 => 6,
+    }
+}
+
+/// Peers kept in SQLite, either in memory or on disk
+pub struct SqliteStore {
+    pool: DbPool,
+}
+
+impl SqliteStore {
+    pub fn new(pool: DbPool) -> result::Result<SqliteStore, StoreError> {
+        db_init(&mut *pool.get()?)?;
+        debug!("DB initialized");
+        Ok(SqliteStore { pool })
+    }
+}
+
+// The announcing peer goes in, or is refreshed, keeping when it started unless it starts again
+fn upsert_peer(conn: &Connection, announce: &Announce) -> Result<()> {
+    let ip = announce.addr.ip().to_string();
+    let port = announce.addr.port() as i32;
+
+    conn.prepare_cached(
+        "INSERT INTO torrent
+            (info_hash, ip, port, family, peer_id, remaining, last_active, started)
+        VALUES (?, ?, ?, ?, ?, ?, strftime('%s', 'now'),
+                CASE WHEN ? THEN strftime('%s', 'now') END)
+        ON CONFLICT (info_hash, ip, port) DO UPDATE SET
+            peer_id     = excluded.peer_id,
+            remaining   = excluded.remaining,
+            last_active = excluded.last_active,
+            started     = COALESCE(excluded.started, torrent.started)",
+    )?
+    .execute(params![
+        &announce.info_hash[..],
+        ip,
+        port,
+        family_column(Family::of(&announce.addr.ip())),
+        &announce.peer_id[..],
+        announce.remaining,
+        announce.event == Event::Started
+    ])?;
+
+    if announce.event == Event::Completed {
+        conn.prepare_cached(
+            "INSERT INTO torrent_stats (info_hash, completed) VALUES (?, 1)
+            ON CONFLICT (info_hash) DO UPDATE SET completed = completed + 1",
+        )?
+        .execute(params![&announce.info_hash[..]])?;
+    }
+    Ok(())
+}
+
+fn select_peers(conn: &Connection, info_hash: &InfoHash, family: Family) -> Result<Vec<Peer>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT ip, port, peer_id, remaining
+         FROM torrent
+         WHERE info_hash = ? AND family = ?
+         ORDER BY remaining != 0",
+    )?;
+    let mut rows = stmt.query(params![&info_hash[..], family_column(family)])?;
+
+    let mut swarm: Vec<Peer> = Vec::new();
+    while let Some(row) = rows.next()? {
+        let ip: String = row.get(0)?;
+        // i32 due to current rusqlite type handling
+        let port: i32 = row.get(1)?;
+        let peer_id: Vec<u8> = row.get(2)?;
+
+        let ip = match IpAddr::from_str(&ip) {
+            Ok(x) => x,
+            Err(_) => {
+                warn!("Skipping peer with unparsable IP {:?}", ip);
+                continue;
+            }
+        };
+
+        let mut peer = Peer {
+            addr: SocketAddr::new(ip, port as u16),
+            peer_id: [0u8; 20],
+            remaining: row.get(3)?,
+        };
+        let len = peer_id.len().min(20);
+        peer.peer_id[..len].copy_from_slice(&peer_id[..len]);
+        swarm.push(peer);
+    }
+
+    Ok(swarm)
+}
+
+fn select_counts(conn: &Connection, info_hash: &InfoHash) -> Result<ScrapeStats> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT COALESCE(SUM(remaining = 0), 0),
+                COALESCE((SELECT completed FROM torrent_stats WHERE info_hash = ?1), 0),
+                COALESCE(SUM(remaining != 0), 0)
+         FROM torrent
+         WHERE info_hash = ?1",
+    )?;
+
+    stmt.query_row([&info_hash[..]], |row| {
+        Ok(ScrapeStats {
+            seeders: row.get(0)?,
+            completed: row.get(1)?,
+            leechers: row.get(2)?,
+        })
+    })
+}
+
+impl PeerStore for SqliteStore {
+    fn announce(&self, announce: &Announce) -> result::Result<(), StoreError> {
+        Ok(upsert_peer(&*self.pool.get()?, announce)?)
+    }
+
+    // One connection and one transaction, rather than waiting on the pool three times
+    fn announce_and_select(
+        &self,
+        announce: &Announce,
+        family: Family,
+    ) -> result::Result<(Vec<Peer>, ScrapeStats), StoreError> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        upsert_peer(&tx, announce)?;
+        let counts = select_counts(&tx, &announce.info_hash)?;
+        let swarm = select_peers(&tx, &announce.info_hash, family)?;
+        tx.commit()?;
+        Ok((swarm, counts))
+    }
+
+    fn peers(
+        &self,
+        info_hash: &InfoHash,
+        family: Family,
+    ) -> result::Result<Vec<Peer>, StoreError> {
+        Ok(select_peers(&*self.pool.get()?, info_hash, family)?)
+    }
+
+    fn counts(&self, info_hash: &InfoHash) -> result::Result<ScrapeStats, StoreError> {
+        Ok(select_counts(&*self.pool.get()?, info_hash)?)
+    }
+
+    fn remove(
+        &self,
+        info_hash: &InfoHash,
+        addr: SocketAddr,
+        peer_id: &PeerId,
+    ) -> result::Result<bool, StoreError> {
+        let conn = self.pool.get()?;
+        let removed = conn.execute(
+            "DELETE FROM torrent
+            WHERE info_hash = ? AND ip = ? AND port = ? AND peer_id = ?",
+            params![
+                &info_hash[..],
+                addr.ip().to_string(),
+                addr.port() as i32,
+                &peer_id[..]
+            ],
+        )?;
+        Ok(removed > 0)
+    }
+
+    fn remove_peer(
+        &self,
+        info_hash: &InfoHash,
+        addr: SocketAddr,
+    ) -> result::Result<bool, StoreError> {
+        let conn = self.pool.get()?;
+        let removed = conn.execute(
+            "DELETE FROM torrent WHERE info_hash = ? AND ip = ? AND port = ?",
+            params![&info_hash[..], addr.ip().to_string(), addr.port() as i32],
+        )?;
+        Ok(removed > 0)
+    }
+
+    fn remove_torrent(&self, info_hash: &InfoHash) -> result::Result<usize, StoreError> {
+        let conn = self.pool.get()?;
+        let removed = conn.execute("DELETE FROM torrent WHERE info_hash = ?", [&info_hash[..]])?;
+        conn.execute("DELETE FROM torrent_stats WHERE info_hash = ?", [&info_hash[..]])?;
+        Ok(removed)
+    }
+
+    fn top(&self, limit: usize) -> result::Result<Vec<(InfoHash, ScrapeStats)>, StoreError> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT t.info_hash,
+                    SUM(t.remaining = 0),
+                    COALESCE(s.completed, 0),
+                    SUM(t.remaining != 0)
+             FROM torrent t LEFT JOIN torrent_stats s ON s.info_hash = t.info_hash
+             GROUP BY t.info_hash
+             ORDER BY COUNT(*) DESC
+             LIMIT ?",
+        )?;
+        let mut rows = stmt.query([limit as i64])?;
+
+        let mut torrents = Vec::new();
+        while let Some(row) = rows.next()? {
+            let hash: Vec<u8> = row.get(0)?;
+            if hash.len() != 20 {
+                continue;
+            }
+            let mut info_hash = [0u8; 20];
+            info_hash.copy_from_slice(&hash);
+            let counts = ScrapeStats {
+                seeders: row.get(1)?,
+                completed: row.get(2)?,
+                leechers: row.get(3)?,
+            };
+            torrents.push((info_hash, counts));
+        }
+        Ok(torrents)
+    }
+
+    fn prune(&self, timeout: i64) -> result::Result<usize, StoreError> {
+        Ok(db_prune(&*self.pool.get()?, timeout)?)
+    }
+
+    fn totals(&self) -> result::Result<Totals, StoreError> {
+        let conn = self.pool.get()?;
+        let totals = conn.query_row(
+            "SELECT COUNT(DISTINCT info_hash),
+                    COALESCE(SUM(remaining = 0), 0),
+                    COALESCE(SUM(remaining != 0), 0)
+             FROM torrent",
+            [],
+            |row| {
+                Ok(Totals {
+                    torrents: row.get(0)?,
+                    seeders: row.get(1)?,
+                    leechers: row.get(2)?,
+                })
+            },
+        )?;
+        Ok(totals)
+    }
+
+    // Fold the WAL back into the database file, a no-op for an in-memory database
+    fn flush(&self) -> result::Result<(), StoreError> {
+        let conn = self.pool.get()?;
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        Ok(())
+    }
+
+    fn set_name(&self, info_hash: &InfoHash, name: &str) -> result::Result<(), StoreError> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "INSERT INTO torrent_name (info_hash, name) VALUES (?, ?)
+            ON CONFLICT (info_hash) DO UPDATE SET name = excluded.name",
+            params![&info_hash[..], name],
+        )?;
+        Ok(())
+    }
+
+    fn name(&self, info_hash: &InfoHash) -> result::Result<Option<String>, StoreError> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached("SELECT name FROM torrent_name WHERE info_hash = ?")?;
+        Ok(stmt
+            .query_row([&info_hash[..]], |row| row.get(0))
+            .optional()?)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::Arc;
+
+    use rusqlite::{params, Connection};
+
+    use super::*;
+    use packet_data_types::Event;
+    use stats::Histogram;
+    use storage::{Announce, Family, PeerStore, StoreError};
+
+    /// An on-disk database removed again on drop. The in-memory one is shared by the whole
+    /// process, tests running side by side would see each other's peers.
+    pub struct TempDb(pub PathBuf);
+
+    impl TempDb {
+        pub fn new(name: &str) -> TempDb {
+            let path = env::temp_dir().join(format!("rtracker-{}-{}.db", name, process::id()));
+            let db = TempDb(path);
+            db.remove();
+            db
+        }
+
+        fn remove(&self) {
+            for suffix in &["", "-wal", "-shm"] {
+                let mut path = self.0.clone().into_os_string();
+                path.push(suffix);
+                let _ = fs::remove_file(path);
+            }
+        }
+
+        pub fn store(&self) -> Result<SqliteStore, StoreError> {
+            self.timed_store(Arc::new(Histogram::default()))
+        }
+
+        // Pool checkouts are counted into wait
+        fn timed_store(&self, wait: Arc<Histogram>) -> Result<SqliteStore, StoreError> {
+            SqliteStore::new(db_connection_pool(2, Some(&self.0), wait)?)
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            self.remove();
+        }
+    }
+
+    pub fn announce(hash: u8, addr: &str, id: u8, remaining: i64) -> Announce {
+        Announce {
+            info_hash: [hash; 20],
+            peer_id: [id; 20],
+            addr: addr.parse().unwrap(),
+            remaining,
+            event: Event::None,
+        }
+    }
+
+    #[test]
+    fn counts_distinct_peers() {
+        let db = TempDb::new("counts");
+        let store = db.store().unwrap();
+        store.announce(&announce(1, "10.0.0.1:6881", 1, 0)).unwrap();
+        store.announce(&announce(1, "10.0.0.2:6881", 2, 0)).unwrap();
+        store.announce(&announce(1, "10.0.0.3:6881", 3, 100)).unwrap();
+        // Announcing again, even with a new peer_id, is the same peer
+        store.announce(&announce(1, "10.0.0.3:6881", 4, 50)).unwrap();
+        store.announce(&announce(1, "10.0.0.1:6881", 1, 0)).unwrap();
+        store.announce(&announce(2, "10.0.0.1:6881", 1, 10)).unwrap();
+
+        let counts = store.counts(&[1; 20]).unwrap();
+        assert_eq!((counts.seeders, counts.leechers), (2, 1));
+        assert_eq!(store.peers(&[1; 20], Family::V4).unwrap().len(), 3);
+        let totals = store.totals().unwrap();
+        assert_eq!((totals.torrents, totals.seeders, totals.leechers), (2, 2, 2));
+    }
+
+    fn checkouts(wait: &Histogram) -> u64 {
+        let mut out = String::new();
+        wait.render(&mut out, "wait", "");
+        let count = out.lines().find(|l| l.starts_with("wait_count ")).unwrap();
+        count["wait_count ".len()..].parse().unwrap()
+    }
+
+    #[test]
+    fn announces_in_one_checkout() {
+        let db = TempDb::new("checkouts");
+        let wait = Arc::new(Histogram::default());
+        let store = db.timed_store(wait.clone()).unwrap();
+        store.announce(&announce(1, "10.0.0.1:6881", 1, 0)).unwrap();
+
+        let before = checkouts(&wait);
+        let mut leecher = announce(1, "10.0.0.2:6881", 2, 10);
+        leecher.event = Event::Completed;
+        let (swarm, counts) = store.announce_and_select(&leecher, Family::V4).unwrap();
+        assert_eq!(checkouts(&wait), before + 1);
+        assert_eq!(swarm.len(), 2);
+        assert!(swarm[0].is_seeder());
+        assert_eq!((counts.seeders, counts.completed, counts.leechers), (1, 1, 1));
+    }
+
+    #[test]
+    fn migrates_peer_id_keyed_table() {
+        let db = TempDb::new("migrate");
+        {
+            // The layout before peers were keyed by their address
+            let conn = Connection::open(&db.0).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE torrent (
+                    info_hash TEXT, ip TEXT, port INTEGER, peer_id TEXT, remaining INTEGER,
+                    last_active INTEGER, started INTEGER,
+                    PRIMARY KEY (info_hash, ip, port, peer_id)
+                );
+                CREATE TABLE torrent_stats (
+                    info_hash TEXT PRIMARY KEY, completed INTEGER NOT NULL DEFAULT 0
+                );",
+            )
+            .unwrap();
+            let mut insert = conn
+                .prepare("INSERT INTO torrent VALUES (?, '10.0.0.1', 6881, ?, ?, ?, NULL)")
+                .unwrap();
+            let hash = &[1u8; 20][..];
+            insert.execute(params![hash, &[2u8; 20][..], 0, 200]).unwrap();
+            insert.execute(params![hash, &[1u8; 20][..], 10, 100]).unwrap();
+        }
+
+        let store = db.store().unwrap();
+        // The newer of the two rows for the address is kept
+        let counts = store.counts(&[1; 20]).unwrap();
+        assert_eq!((counts.seeders, counts.leechers), (1, 0));
+        store.announce(&announce(1, "10.0.0.1:6881", 3, 5)).unwrap();
+        let counts = store.counts(&[1; 20]).unwrap();
+        assert_eq!((counts.seeders, counts.leechers), (0, 1));
+
+        let conn = Connection::open(&db.0).unwrap();
+        let version: i32 = conn.query_row("PRAGMA user_version", [], |r| r.get(0)).unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn migrates_table_without_family() {
+        let db = TempDb::new("family");
+        {
+            // The layout before swarms were split by address family
+            let conn = Connection::open(&db.0).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE torrent (
+                    info_hash TEXT, ip TEXT, port INTEGER, peer_id TEXT, remaining INTEGER,
+                    last_active INTEGER, started INTEGER,
+                    PRIMARY KEY (info_hash, ip, port)
+                );",
+            )
+            .unwrap();
+            let mut insert = conn
+                .prepare("INSERT INTO torrent VALUES (?, ?, 6881, ?, 0, strftime('%s'), NULL)")
+                .unwrap();
+            let hash = &[1u8; 20][..];
+            insert.execute(params![hash, "10.0.0.1", &[1u8; 20][..]]).unwrap();
+            insert.execute(params![hash, "2001:db8::1", &[2u8; 20][..]]).unwrap();
+        }
+
+        let store = db.store().unwrap();
+        store.announce(&announce(1, "10.0.0.2:6881", 3, 0)).unwrap();
+        store.announce(&announce(1, "[2001:db8::2]:6881", 4, 0)).unwrap();
+        let v4 = store.peers(&[1; 20], Family::V4).unwrap();
+        let v6 = store.peers(&[1; 20], Family::V6).unwrap();
+        assert_eq!(v4.len(), 2);
+        assert_eq!(v6.len(), 2);
+        assert!(v4.iter().all(|p| p.addr.is_ipv4()));
+        assert!(v6.iter().all(|p| p.addr.is_ipv6()));
+    }
+
+    #[test]
+    fn refuses_newer_schema() {
+        let db = TempDb::new("newer");
+        {
+            let mut conn = Connection::open(&db.0).unwrap();
+            db_init(&mut conn).unwrap();
+            conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1).unwrap();
+        }
+        match db.store() {
+            Err(StoreError::Schema(_)) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("opened a database from a newer version"),
+        }
+    }
 }
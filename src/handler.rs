@@ -13,194 +13,790 @@
 //   You should have received a copy of the GNU Affero General Public License
 //   along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use bincode::serialize;
-use chrono::prelude::Utc;
-use rand::{thread_rng, Rng};
-use rusqlite::*;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
 
-use database::PoolCon;
+use access::AccessList;
+#[cfg(target_os = "linux")]
+use batch;
+use blocklist::Blocklist;
+use config::ServerConfig;
+use connection_id::{ConnectionIds, PROTOCOL_ID};
+use database::DbPool;
 use packet_data_types::*;
 use parse_packets::*;
+use ratelimit::{RateLimiter, Verdict};
+use stats::{incr, Stats};
+use storage::{Announce, Family, Peer, PeerStore, StoreError};
+use torrents::TorrentDir;
 
-// struct used by update announce to make passing data easy (vs. 4 more parameters)
-struct ID {
-    info_hash:  Vec<u8>,
-    ip:         String,
-    port:       u16,
-    peer_id:    Vec<u8>,
-    remaining:  i64,
+/// Largest datagram read, anything longer is cut short
+pub const MAX_PACKET_SIZE: usize = 1500;
+/// How long a worker waits for a packet before checking whether the tracker is stopping
+pub const STOP_POLL: Duration = Duration::from_millis(250);
+/// How long an idle TCP listener sleeps between accepts, short as it delays a new connection
+pub const ACCEPT_POLL: Duration = Duration::from_millis(10);
+
+/// What a config reload replaces, swapped as a whole
+pub struct Settings {
+    pub config:    ServerConfig,
+    pub access:    AccessList,
+    pub blocklist: Blocklist,
+}
+
+/// State shared by every packet handler
+pub struct Tracker {
+    pub settings: RwLock<Arc<Settings>>,
+    pub store:    Arc<dyn PeerStore>,
+    pub ids:      ConnectionIds,
+    pub stats:    Stats,
+    pub limiter:  RateLimiter,
+    pub started:  Instant,
+    /// The --conf argument, read again on reload
+    pub config_path: String,
+    /// Holds the access_list table when the store is SQLite
+    pub db_pool: Option<DbPool>,
+    // Also held through a reload, so two never interleave
+    pub torrent_dir: Mutex<Option<TorrentDir>>,
+    /// Set by SIGINT or SIGTERM, workers finish the packets in hand and return
+    pub stopping: Arc<AtomicBool>,
+    // Held through a prune, so a flush waits for one in progress
+    pub prune_lock: Mutex<()>,
+}
+
+impl Tracker {
+    /// The settings in force, a reload does not change the ones already handed out
+    pub fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Remove the peers not heard from in peer_timeout, returning how many there were
+    pub fn prune(&self) -> result::Result<usize, StoreError> {
+        let _pruning = self.prune_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = &self.stats;
+        let start = Instant::now();
+        let peer_timeout = self.settings().config.peer_timeout;
+        let pruned = self.store.prune(i64::from(peer_timeout));
+        if let Ok(x) = pruned {
+            stats.pruned_peers.fetch_add(x as u64, Ordering::Relaxed);
+        }
+        incr(&stats.prunes);
+        let elapsed = start.elapsed().as_micros() as u64;
+        stats.prune_micros.store(elapsed, Ordering::Relaxed);
+        pruned
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::Relaxed)
+    }
+
+    /// Make the store durable once any prune in progress is done
+    pub fn flush(&self) -> result::Result<(), StoreError> {
+        let _pruning = self.prune_lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.store.flush()
+    }
+}
+
+#[derive(Debug)]
+pub enum HandlerError {
+    Parse(ParseError),
+    Storage(StoreError),
+    InvalidConnectionId,
+    /// An info hash the access list does not allow
+    TorrentNotAllowed,
+    /// A connect that did not carry the protocol ID
+    MissingProtocolId,
+    UnsupportedAction(i32),
+}
+
+impl HandlerError {
+    /// The error to send back to the client, None when the packet should be dropped
+    fn reply(&self) -> Option<TrackerError> {
+        match *self {
+            HandlerError::Parse(_) => Some(TrackerError::MalformedRequest),
+            HandlerError::InvalidConnectionId => Some(TrackerError::BadConnectionId),
+            HandlerError::TorrentNotAllowed => Some(TrackerError::TorrentNotAllowed),
+            HandlerError::UnsupportedAction(_) => Some(TrackerError::UnknownAction),
+            HandlerError::Storage(_) | HandlerError::MissingProtocolId => None,
+        }
+    }
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HandlerError::Parse(ref e) => write!(f, "parse error: {}", e),
+            HandlerError::Storage(ref e) => write!(f, "storage error: {}", e),
+            HandlerError::InvalidConnectionId => write!(f, "invalid connection ID"),
+            HandlerError::TorrentNotAllowed => write!(f, "torrent not allowed"),
+            HandlerError::MissingProtocolId => write!(f, "connect without the protocol ID"),
+            HandlerError::UnsupportedAction(x) => write!(f, "unsupported action {}", x),
+        }
+    }
 }
 
-pub type TrackerData = (Vec<(String, i32)>, i32, i32);
+impl From<ParseError> for HandlerError {
+    fn from(e: ParseError) -> HandlerError {
+        HandlerError::Parse(e)
+    }
+}
 
-// Generate a UUID to make the client happy
-fn gen_uuid() -> i64 {
-    let mut rng = thread_rng();
-    let mut uuid: i64 = Utc::now().timestamp();
-    uuid <<= 32;
-    uuid | rng.gen::<u32>() as i64
+impl From<StoreError> for HandlerError {
+    fn from(e: StoreError) -> HandlerError {
+        HandlerError::Storage(e)
+    }
 }
 
 // On announce, update the client's remaining and last_active info
-// Get the Seeders and Leechers for the provided info_hash
-fn update_announce(conn: PoolCon, id: &ID, data: &ClientAnnounce) -> Result<TrackerData> {
-    // [u8; 20] -> Vec<u8>
-    let mut hash: Vec<u8> = Vec::new();
-    hash.extend_from_slice(&data.info_hash);
+// Get the swarm, Seeders and Leechers for the provided info_hash
+fn update_announce(
+    store: &dyn PeerStore,
+    announce: &Announce,
+    family: Family,
+    blocklist: &Blocklist,
+) -> result::Result<(Vec<Peer>, ScrapeStats), StoreError> {
     debug!("ClientAnnounce");
-    debug!("hash: {:?}", hash);
-
-    // Update the user info
-    match conn.execute(
-        "INSERT OR REPLACE INTO torrent (info_hash, ip, port, peer_id, remaining, last_active)
-        VALUES (?, ?, ?, ?, ?, strftime('%s', 'now'))",
-        params![
-            id.info_hash,
-            id.ip,
-            (id.port as i32),
-            id.peer_id,
-            id.remaining
-        ],
-    ) {
-        Ok(_) => (),
-        Err(x) => panic!("{:?}", x),
-    }
-
-    // Info Hash swarm IP and ports
-    // i32 due to current rusqlite type handling
-    let mut swarm: Vec<(String, i32)> = Vec::new();
-
-    // Get Seeders
-    let mut stmt = conn.prepare(
-        "SELECT ip,port,COUNT(*)
-         FROM torrent
-         WHERE info_hash = ? AND remaining = 0
-         GROUP BY ip,port",
-    )?;
-    let mut rows = stmt.query(&[&hash])?;
-
-    // Each row produces a count, update it as we continue along
-    let mut seeders: i32 = 0;
-    while let Some(result_row) = rows.next()? {
-        let ip: String = result_row.get(0)?;
-        let port: i32 = result_row.get(1)?;
-
-        swarm.push((ip, port));
-
-        let count: i32 = result_row.get(2)?;
-        if count > seeders {
-            seeders = count;
-        }
-    }
-
-    // Get Leechers
-    let mut stmt = conn.prepare(
-        "SELECT ip,port,COUNT(*)
-         FROM torrent
-         WHERE info_hash = ? AND remaining > 0
-         GROUP BY ip,port",
-    )?;
-    let mut rows = stmt.query(&[&hash])?;
-
-    // Each row produces a count, update it as we continue along
-    let mut leechers: i32 = 0;
-    while let Some(result_row) = rows.next()? {
-        let ip: String = result_row.get(0)?;
-        let port: i32 = result_row.get(1)?;
-
-        swarm.push((ip, port));
-
-        let count: i32 = result_row.get(2)?;
-        if count > leechers {
-            leechers = count;
-        }
+    debug!("hash: {:?}", announce.info_hash);
+    debug!("event: {:?}", announce.event);
+
+    // The source was not blocked but the ip field may name a blocked address, which must not
+    // end up in the swarm
+    if blocklist.contains(announce.addr.ip()) {
+        debug!("Not recording blocked address {}", announce.addr);
+        let counts = store.counts(&announce.info_hash)?;
+        return Ok((store.peers(&announce.info_hash, family)?, counts));
+    }
+
+    // A stopping peer is removed right away and has no use for the swarm
+    if announce.event == Event::Stopped {
+        store.remove(&announce.info_hash, announce.addr, &announce.peer_id)?;
+        return Ok((Vec::new(), store.counts(&announce.info_hash)?));
     }
 
     // Return the swarm, seeders, and leechers for packeting
-    Ok((swarm, seeders, leechers))
+    store.announce_and_select(announce, family)
 }
 
-pub fn handle_received_packet(packet: Vec<u8>, src: SocketAddr, sock: UdpSocket, conn: PoolCon) {
-    debug!("Begin parsing received packet!");
-    let (packet_header, packet_body) = packet.split_at(16);
-    debug!("Packet Size: {:?}", packet.len());
+// Pick a random sample of the swarm worth handing to the announcing peer.
+// It never gets itself back, and a seeder has no use for other seeders.
+fn select_peers(
+    swarm: Vec<Peer>,
+    announce: &Announce,
+    num_want: i32,
+    scfg: &ServerConfig,
+    blocklist: &Blocklist,
+) -> Vec<Peer> {
+    // -1 (or any negative) means the client leaves it up to us
+    let wanted = if num_want < 0 {
+        scfg.default_num_want
+    } else {
+        (num_want as usize).min(scfg.max_num_want)
+    };
+
+    // Peers that joined before their network was blocked stay until they time out, they are
+    // just never handed out
+    let seeding = announce.remaining == 0;
+    let candidates: Vec<Peer> = swarm
+        .into_iter()
+        .filter(|p| p.addr != announce.addr && !(seeding && p.is_seeder()))
+        .filter(|p| !blocklist.contains(p.addr.ip()))
+        .collect();
+    debug!("Selecting {} of {} peers", wanted, candidates.len());
+
+    candidates
+        .choose_multiple(&mut thread_rng(), wanted)
+        .cloned()
+        .collect()
+}
+
+/// Record an announce and pick the peers of `family` to answer it with.
+/// Shared by every frontend so UDP and HTTP clients see the same swarm.
+pub fn process_announce(
+    tracker: &Tracker,
+    settings: &Settings,
+    announce: &Announce,
+    num_want: i32,
+    family: Family,
+) -> result::Result<(Vec<Peer>, ScrapeStats), StoreError> {
+    let blocklist = &settings.blocklist;
+    let (swarm, counts) = update_announce(&*tracker.store, announce, family, blocklist)?;
+    Ok((
+        select_peers(swarm, announce, num_want, &settings.config, blocklist),
+        counts,
+    ))
+}
+
+// Build the response to a packet, or the reason there is none
+fn respond(
+    header: &PacketHeader,
+    packet_body: &[u8],
+    src: SocketAddr,
+    tracker: &Tracker,
+    settings: &Settings,
+) -> result::Result<Vec<u8>, HandlerError> {
+    let ids = &tracker.ids;
+    let store = &*tracker.store;
+
+    // Anything other than a connect must carry a connection ID we handed out
+    if header.action != 0 && !ids.validate(header.connection_id, src.ip()) {
+        debug!("Invalid connection ID {:x} from {}", header.connection_id, src);
+        return Err(HandlerError::InvalidConnectionId);
+    }
 
-    // parse the header to act on it
-    let header: PacketHeader = parse_header(packet_header);
-    debug!("Header: {:?}", header);
-    debug!("Action: {}", header.action as i32);
-    debug!("Packet Body (PB):");
-    debug!("(PB) Length: {}", packet_body.len());
     match header.action {
         0 => {
             debug!("Conid? {}", header.connection_id);
-            // Magic number according to
-            // http://www.rasterbar.com/products/libtorrent/udp_tracker_protocol.html
-            // if header.connection_id == 0x41727101980 {
+            if header.connection_id != PROTOCOL_ID {
+                return Err(HandlerError::MissingProtocolId);
+            }
+
             // We need to generate an unique id for this client.
-            // 32bits of the current time in nanoseconds combined with 32bits of
-            // random numbers
-            let uuid = gen_uuid();
+            // 32bits of the current time combined with 32bits of an HMAC over the
+            // client's IP and that time
+            let uuid = ids.issue(src.ip());
 
             // debugs
             debug!("UUID: {}", uuid);
 
-            // Now they're in the db, let's say hi
-            let encoded = encode_server_connect(uuid, header.transaction_id);
-            sock.send_to(&encoded, src).unwrap();
-            //} else {
-            //}
+            // Let's say hi
+            Ok(encode_server_connect(uuid, header.transaction_id))
         }
         1 => {
             // Decode the announce info
-            let ca_decoded: ClientAnnounce = decode_client_announce(packet_body);
+            let ca_decoded: ClientAnnounce = decode_client_announce(packet_body)?;
+            if !settings.access.allowed(&ca_decoded.info_hash) {
+                return Err(HandlerError::TorrentNotAllowed);
+            }
 
-            // handle an IP of 0
+            // handle an IP of 0, the field only has room for an IPv4 address so IPv6 clients
+            // are always taken at their source address
             let ip_field = ca_decoded.ip;
-            let ip: String = if ip_field == 0 {
-                match src.ip() {
-                    IpAddr::V4(x) => x.to_string(),
-                    IpAddr::V6(y) => y.to_string(),
-                }
-            } else {
-                // This is guaranteed to be a u32 and thus have a Vec<u8>.len() of 4
-                let x: Vec<u8> = serialize(&ca_decoded.ip).unwrap();
-                Ipv4Addr::new(x[0], x[1], x[2], x[3]).to_string()
+            let ip: IpAddr = match src.ip() {
+                IpAddr::V4(_) if ip_field != 0 => IpAddr::V4(Ipv4Addr::from(ip_field)),
+                x => x,
             };
 
-            // Package up the announce info for DB consumption
-            let mut hash: Vec<u8> = Vec::with_capacity(20);
-            hash.extend_from_slice(&ca_decoded.info_hash);
-
-            let mut peer_id: Vec<u8> = Vec::with_capacity(20);
-            peer_id.extend_from_slice(&ca_decoded.peer_id);
-
-            let id = ID {
-                info_hash: hash,
-                ip,
-                port: ca_decoded.port,
-                peer_id,
+            // Package up the announce info for the store
+            let announce = Announce {
+                info_hash: ca_decoded.info_hash,
+                peer_id: ca_decoded.peer_id,
+                addr: SocketAddr::new(ip, ca_decoded.port),
                 remaining: ca_decoded.remaining,
+                event: Event::from_i32(ca_decoded.event),
             };
 
             // Get the swarm, seeder, and leecher info
-            let (swarm, seeders, leechers) = update_announce(conn, &id, &ca_decoded).unwrap();
+            // Answer with peers the client can reach over the family it asked on
+            let family = Family::of(&src.ip());
+            let (swarm, counts) =
+                process_announce(tracker, settings, &announce, ca_decoded.num_want, family)?;
+            let swarm = swarm.into_iter().map(|p| p.addr).collect();
 
             // Send it back to the client
-            let serv_announce = encode_server_announce(
+            Ok(encode_server_announce(
                 header.transaction_id,
                 swarm,
-                ca_decoded.num_want,
-                leechers,
-                seeders,
-            );
-            sock.send_to(&serv_announce, src).unwrap();
+                settings.config.announce_interval,
+                counts.leechers,
+                counts.seeders,
+            ))
+        }
+        2 => {
+            // Decode the requested info hashes
+            let hashes = decode_client_scrape(packet_body)?;
+            debug!("Scrape of {} hashes", hashes.len());
+            if !hashes.iter().all(|h| settings.access.allowed(h)) {
+                return Err(HandlerError::TorrentNotAllowed);
+            }
+
+            // Get the seeder, completed, and leecher info of each hash
+            let stats = store.scrape(&hashes)?;
+
+            // Send it back to the client
+            Ok(encode_server_scrape(header.transaction_id, stats))
+        }
+        action => Err(HandlerError::UnsupportedAction(action)),
+    }
+}
+
+/// A dual-stack socket sees IPv4 clients as IPv4-mapped IPv6 addresses, they are still IPv4
+/// peers and must be tracked as such
+pub fn canonical_addr(src: SocketAddr) -> SocketAddr {
+    match src.ip() {
+        IpAddr::V6(ip6) => match ip6.to_ipv4_mapped() {
+            Some(ip4) => SocketAddr::new(IpAddr::V4(ip4), src.port()),
+            None => src,
+        },
+        IpAddr::V4(_) => src,
+    }
+}
+
+fn send(sock: &UdpSocket, packet: &[u8], src: SocketAddr) {
+    if let Err(e) = sock.send_to(packet, src) {
+        warn!("Failed to send {} bytes to {}: {}", packet.len(), src, e);
+    }
+}
+
+/// The answer to a datagram received from `src`, None when it is dropped.
+/// Replies go to `src` as it is, the socket's own idea of the address.
+pub fn handle_datagram(packet: &[u8], src: SocketAddr, tracker: &Tracker) -> Option<Vec<u8>> {
+    let stats = &tracker.stats;
+    let settings = tracker.settings();
+    // Banned networks do not even get their packets parsed
+    if settings.blocklist.contains(src.ip()) {
+        debug!("Dropping a packet from blocked {}", src);
+        incr(&stats.blocked);
+        return None;
+    }
+    if packet.len() < HEADER_SIZE {
+        debug!("Received a tiny packet (size: {}), ignoring", packet.len());
+        incr(&stats.dropped_packets);
+        return None;
+    }
+
+    debug!("Begin parsing received packet!");
+    debug!("Packet Size: {:?}", packet.len());
+    incr(&stats.packets_received);
+
+    // parse the header to act on it, without one there is nobody to answer
+    let header: PacketHeader = match parse_header(packet) {
+        Ok(x) => x,
+        Err(e) => {
+            debug!("Dropping packet from {} with a bad header: {}", src, e);
+            incr(&stats.parse_errors);
+            incr(&stats.dropped_packets);
+            return None;
+        }
+    };
+    let packet_body = &packet[HEADER_SIZE..];
+    let action_stats = stats.action(header.action);
+    match action_stats {
+        Some(a) => incr(&a.received),
+        None => incr(&stats.unknown_action),
+    }
+
+    let client = canonical_addr(src);
+    // Announce responses dwarf the request, so spoofed floods must not get answers
+    match tracker.limiter.check(client.ip()) {
+        Verdict::Allow => (),
+        Verdict::Reply => {
+            incr(&stats.rate_limited);
+            incr(&stats.error_responses);
+            return Some(encode_error(header.transaction_id, TrackerError::RateLimited));
+        }
+        Verdict::Drop => {
+            debug!("Dropping rate limited packet from {}", src);
+            incr(&stats.rate_limited);
+            incr(&stats.dropped_packets);
+            return None;
+        }
+    }
+
+    debug!("Header: {:?}", header);
+    debug!("Action: {}", header.action);
+    debug!("Packet Body (PB):");
+    debug!("(PB) Length: {}", packet_body.len());
+
+    let start = Instant::now();
+    let result = respond(&header, packet_body, client, tracker, &settings);
+    if let Some(a) = action_stats {
+        a.latency.observe(start.elapsed());
+        if result.is_ok() {
+            incr(&a.answered);
+        }
+    }
+
+    match result {
+        Ok(response) => Some(response),
+        Err(e) => {
+            debug!("Packet from {} failed: {}", src, e);
+            if let HandlerError::Parse(_) = e {
+                incr(&stats.parse_errors);
+            }
+
+            match e.reply() {
+                Some(err) => {
+                    incr(&stats.error_responses);
+                    Some(encode_error(header.transaction_id, err))
+                }
+                None => {
+                    incr(&stats.dropped_packets);
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Whether a receive failed only because nothing arrived within STOP_POLL
+pub fn timed_out(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+}
+
+/// The next connection on a non-blocking `listener`, or None once the tracker is stopping
+pub fn accept(
+    listener: &TcpListener,
+    tracker: &Tracker,
+) -> Option<io::Result<(TcpStream, SocketAddr)>> {
+    while !tracker.is_stopping() {
+        match listener.accept() {
+            // Accepted sockets inherit non-blocking on some platforms, their reads are timed
+            Ok((stream, src)) => return Some(stream.set_nonblocking(false).map(|_| (stream, src))),
+            Err(ref e) if timed_out(e) => thread::sleep(ACCEPT_POLL),
+            Err(e) => return Some(Err(e)),
+        }
+    }
+    None
+}
+
+/// Answer packets arriving on `sock` until the tracker is stopping.
+/// Run by every worker thread, each with its own handle to the socket.
+pub fn serve(sock: UdpSocket, tracker: Arc<Tracker>) {
+    #[cfg(target_os = "linux")]
+    {
+        if tracker.settings().config.batch_size > 1 {
+            return batch::serve(sock, tracker);
+        }
+    }
+
+    // UDP packet max, reused for every packet this worker receives
+    let mut buf = [0u8; MAX_PACKET_SIZE];
+    while !tracker.is_stopping() {
+        let (amt, src) = match sock.recv_from(&mut buf) {
+            Ok(x) => x,
+            Err(ref e) if timed_out(e) => continue,
+            Err(e) => {
+                warn!("Failed to receive a packet: {}", e);
+                continue;
+            }
+        };
+        if let Some(reply) = handle_datagram(&buf[..amt], src, &tracker) {
+            send(&sock, &reply, src);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::time::Instant;
+
+    use super::*;
+    use access::AccessList;
+    use blocklist::Blocklist;
+    use config::ServerConfig;
+    use connection_id::{ConnectionIds, PROTOCOL_ID};
+    use database::tests::TempDb;
+    use memory::MemoryStore;
+    use ratelimit::{Limit, RateLimiter};
+    use stats::Stats;
+    use storage::PeerStore;
+
+    /// A tracker with the default config and no rate limits
+    pub fn tracker(store: Arc<dyn PeerStore>) -> Tracker {
+        let off = Limit { rate: 0, burst: 0 };
+        let settings = Settings {
+            config: ServerConfig::default(),
+            access: AccessList::open(),
+            blocklist: Blocklist::default(),
+        };
+        Tracker {
+            settings: RwLock::new(Arc::new(settings)),
+            store,
+            ids: ConnectionIds::new(),
+            stats: Stats::default(),
+            limiter: RateLimiter::new(off, off, off),
+            started: Instant::now(),
+            config_path: String::new(),
+            db_pool: None,
+            torrent_dir: Mutex::new(None),
+            stopping: Arc::new(AtomicBool::new(false)),
+            prune_lock: Mutex::new(()),
+        }
+    }
+
+    fn be_i32(bytes: &[u8]) -> i32 {
+        let mut x = [0u8; 4];
+        x.copy_from_slice(&bytes[..4]);
+        i32::from_be_bytes(x)
+    }
+
+    pub fn connect(tracker: &Tracker, src: SocketAddr) -> i64 {
+        let mut packet = PROTOCOL_ID.to_be_bytes().to_vec();
+        packet.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 7]);
+        let reply = handle_datagram(&packet, src, tracker).unwrap();
+        assert_eq!(be_i32(&reply), 0);
+        let mut id = [0u8; 8];
+        id.copy_from_slice(&reply[8..16]);
+        i64::from_be_bytes(id)
+    }
+
+    /// The announce reply of a client at src, its counts and compact peers
+    pub struct Reply {
+        pub leechers: i32,
+        pub seeders:  i32,
+        pub peers:    Vec<u8>,
+    }
+
+    pub fn announce(
+        tracker: &Tracker,
+        src: SocketAddr,
+        peer_id: u8,
+        remaining: i64,
+        event: i32,
+    ) -> Reply {
+        let id = connect(tracker, src);
+        let mut packet = id.to_be_bytes().to_vec();
+        packet.extend_from_slice(&1i32.to_be_bytes());
+        packet.extend_from_slice(&9i32.to_be_bytes());
+        packet.extend_from_slice(&[1; 20]);
+        packet.extend_from_slice(&[peer_id; 20]);
+        packet.extend_from_slice(&0i64.to_be_bytes());
+        packet.extend_from_slice(&remaining.to_be_bytes());
+        packet.extend_from_slice(&0i64.to_be_bytes());
+        packet.extend_from_slice(&event.to_be_bytes());
+        packet.extend_from_slice(&[0; 8]);
+        packet.extend_from_slice(&(-1i32).to_be_bytes());
+        packet.extend_from_slice(&src.port().to_be_bytes());
+
+        let reply = handle_datagram(&packet, src, tracker).unwrap();
+        assert_eq!(be_i32(&reply), 1, "not an announce reply: {:?}", reply);
+        assert_eq!(be_i32(&reply[4..]), 9);
+        Reply {
+            leechers: be_i32(&reply[12..]),
+            seeders: be_i32(&reply[16..]),
+            peers: reply[20..].to_vec(),
         }
-        _ => {
-            let err_packet = encode_error(header.transaction_id, "Unsupported Action");
-            sock.send_to(&err_packet, src).unwrap();
+    }
+
+    /// The scrape reply to a client at src, the reply header is checked and stripped
+    fn scrape(tracker: &Tracker, src: SocketAddr, hashes: &[[u8; 20]]) -> Vec<u8> {
+        let id = connect(tracker, src);
+        let mut packet = id.to_be_bytes().to_vec();
+        packet.extend_from_slice(&2i32.to_be_bytes());
+        packet.extend_from_slice(&5i32.to_be_bytes());
+        for hash in hashes {
+            packet.extend_from_slice(hash);
         }
+
+        let reply = handle_datagram(&packet, src, tracker).unwrap();
+        assert_eq!(be_i32(&reply), 2, "not a scrape reply: {:?}", reply);
+        assert_eq!(be_i32(&reply[4..]), 5);
+        reply[8..].to_vec()
+    }
+
+    // Seeders, completed and leechers of a known hash, zeros for one nobody announced
+    fn scrape_counts(store: Arc<dyn PeerStore>) {
+        let tracker = tracker(store);
+        let addr = |i: u8| SocketAddr::from(([10, 0, 0, i], 6881));
+        announce(&tracker, addr(1), 1, 0, 2);
+        announce(&tracker, addr(2), 2, 0, 1);
+        announce(&tracker, addr(3), 3, 100, 2);
+
+        let reply = scrape(&tracker, addr(4), &[[1; 20], [2; 20]]);
+        assert_eq!(reply.len(), 2 * 12);
+        assert_eq!((be_i32(&reply), be_i32(&reply[4..]), be_i32(&reply[8..])), (2, 1, 1));
+        assert_eq!(reply[12..], [0; 12]);
+    }
+
+    #[test]
+    fn memory_scrape_counts() {
+        scrape_counts(Arc::new(MemoryStore::new(4)));
+    }
+
+    #[test]
+    fn sqlite_scrape_counts() {
+        let db = TempDb::new("handler-scrape");
+        scrape_counts(Arc::new(db.store().unwrap()));
+    }
+
+    #[test]
+    fn scrape_answers_at_most_74_hashes() {
+        let tracker = tracker(Arc::new(MemoryStore::new(4)));
+        let hashes: Vec<[u8; 20]> = (0..80).map(|i| [i; 20]).collect();
+        let reply = scrape(&tracker, SocketAddr::from(([10, 0, 0, 1], 6881)), &hashes);
+        assert_eq!(reply.len(), 74 * 12);
+    }
+
+    #[test]
+    fn announce_needs_its_own_connection_id() {
+        let tracker = tracker(Arc::new(MemoryStore::new(4)));
+        let src = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let other = SocketAddr::from(([10, 0, 0, 2], 6881));
+        for id in &[connect(&tracker, other), 0x1234_5678] {
+            let mut packet = id.to_be_bytes().to_vec();
+            packet.extend_from_slice(&1i32.to_be_bytes());
+            packet.extend_from_slice(&9i32.to_be_bytes());
+            packet.extend_from_slice(&[0; ANNOUNCE_SIZE]);
+
+            let reply = handle_datagram(&packet, src, &tracker).unwrap();
+            assert_eq!(be_i32(&reply), 3);
+            assert_eq!(be_i32(&reply[4..]), 9);
+            assert_eq!(reply[8..], *TrackerError::BadConnectionId.message().as_bytes());
+        }
+        assert_eq!(tracker.store.scrape(&[[0; 20]]).unwrap()[0].leechers, 0);
+    }
+
+    /// The typed error for a packet from a connected client, and the message it is sent
+    fn refused(tracker: &Tracker, action: i32, body: &[u8]) -> (HandlerError, Vec<u8>) {
+        let src = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let mut packet = connect(tracker, src).to_be_bytes().to_vec();
+        packet.extend_from_slice(&action.to_be_bytes());
+        packet.extend_from_slice(&9i32.to_be_bytes());
+        packet.extend_from_slice(body);
+
+        let header = parse_header(&packet).unwrap();
+        let settings = tracker.settings();
+        let error = respond(&header, &packet[HEADER_SIZE..], src, tracker, &settings);
+        let reply = handle_datagram(&packet, src, tracker).unwrap();
+        assert_eq!(be_i32(&reply), 3, "not an error reply: {:?}", reply);
+        assert_eq!(be_i32(&reply[4..]), 9);
+        (error.unwrap_err(), reply[8..].to_vec())
+    }
+
+    fn malformed() -> Vec<u8> {
+        TrackerError::MalformedRequest.message().as_bytes().to_vec()
+    }
+
+    #[test]
+    fn truncated_packets() {
+        let tracker = tracker(Arc::new(MemoryStore::new(4)));
+        // Too short for a header, there is no transaction ID to answer with
+        let tiny = handle_datagram(&[0; 10], SocketAddr::from(([10, 0, 0, 1], 6881)), &tracker);
+        assert!(tiny.is_none());
+        assert_eq!(tracker.stats.dropped_packets.load(Ordering::Relaxed), 1);
+
+        match refused(&tracker, 1, &[0; 40]) {
+            (HandlerError::Parse(ParseError::TooShort { needed: 82, got: 40 }), message) => {
+                assert_eq!(message, malformed())
+            }
+            x => panic!("expected a short announce, got {:?}", x),
+        }
+        match refused(&tracker, 2, &[0; 10]) {
+            (HandlerError::Parse(ParseError::NoInfoHash), message) => {
+                assert_eq!(message, malformed())
+            }
+            x => panic!("expected a scrape without hashes, got {:?}", x),
+        }
+        assert_eq!(tracker.stats.parse_errors.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn bad_action() {
+        let tracker = tracker(Arc::new(MemoryStore::new(4)));
+        for &action in &[7, -1] {
+            match refused(&tracker, action, &[0; 20]) {
+                (HandlerError::UnsupportedAction(x), message) => {
+                    assert_eq!(x, action);
+                    assert_eq!(message, TrackerError::UnknownAction.message().as_bytes());
+                }
+                x => panic!("expected an unsupported action, got {:?}", x),
+            }
+        }
+        assert_eq!(tracker.stats.unknown_action.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn oversize_packets() {
+        let tracker = tracker(Arc::new(MemoryStore::new(4)));
+        let src = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let big = [0; 2 * MAX_PACKET_SIZE];
+        match refused(&tracker, 7, &big) {
+            (HandlerError::UnsupportedAction(7), _) => (),
+            x => panic!("expected an unsupported action, got {:?}", x),
+        }
+
+        // Bytes past an announce are extensions, past the last whole hash of a scrape ignored
+        let mut packet = connect(&tracker, src).to_be_bytes().to_vec();
+        packet.extend_from_slice(&1i32.to_be_bytes());
+        packet.extend_from_slice(&9i32.to_be_bytes());
+        packet.extend_from_slice(&big);
+        let reply = handle_datagram(&packet, src, &tracker).unwrap();
+        assert_eq!(be_i32(&reply), 1);
+        packet[8..12].copy_from_slice(&2i32.to_be_bytes());
+        let reply = handle_datagram(&packet, src, &tracker).unwrap();
+        assert_eq!(reply.len(), 8 + 74 * 12);
+    }
+
+    fn swarm_counts(store: Arc<dyn PeerStore>) {
+        let tracker = tracker(store);
+        let addr = |i: u8| SocketAddr::from(([10, 0, 0, i], 6881));
+        for i in 1..4 {
+            announce(&tracker, addr(i), i, 0, 2);
+        }
+        announce(&tracker, addr(4), 4, 100, 2);
+        let reply = announce(&tracker, addr(5), 5, 100, 2);
+        assert_eq!((reply.seeders, reply.leechers), (3, 2));
+        // Everyone but the leecher itself, 6 bytes each
+        assert_eq!(reply.peers.len(), 4 * 6);
+
+        // A client restarting with a new peer_id replaces itself, a seeder gets no seeders
+        let reply = announce(&tracker, addr(4), 40, 0, 0);
+        assert_eq!((reply.seeders, reply.leechers), (4, 1));
+        assert_eq!(reply.peers.len(), 6);
+
+        let reply = announce(&tracker, addr(5), 5, 100, 3);
+        assert_eq!((reply.seeders, reply.leechers), (4, 0));
+        assert!(reply.peers.is_empty());
+    }
+
+    // Both families in one swarm, each client only gets peers it can reach
+    fn dual_stack(store: Arc<dyn PeerStore>) {
+        let tracker = tracker(store);
+        let v4 = |i: u8| SocketAddr::from(([10, 0, 0, i], 6881));
+        let v6 = |i: u16| SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, i], 6881));
+        announce(&tracker, v4(1), 1, 0, 2);
+        announce(&tracker, v6(1), 2, 0, 2);
+        announce(&tracker, v6(2), 3, 0, 2);
+        // An IPv4 client of a dual-stack socket
+        let mapped = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).to_ipv6_mapped().into(), 6881);
+        announce(&tracker, mapped, 4, 0, 2);
+
+        let reply = announce(&tracker, v4(3), 5, 100, 2);
+        assert_eq!((reply.seeders, reply.leechers), (4, 1));
+        assert_eq!(reply.peers.len(), 2 * 6);
+        for peer in reply.peers.chunks(6) {
+            assert!(peer[..4] == [10, 0, 0, 1] || peer[..4] == [10, 0, 0, 2]);
+        }
+
+        let reply = announce(&tracker, v6(3), 6, 100, 2);
+        assert_eq!(reply.peers.len(), 2 * 18);
+        for peer in reply.peers.chunks(18) {
+            assert_eq!(peer[..4], [0x20, 0x01, 0x0d, 0xb8]);
+            assert_eq!(peer[16..], 6881u16.to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn memory_dual_stack() {
+        dual_stack(Arc::new(MemoryStore::new(4)));
+    }
+
+    #[test]
+    fn sqlite_dual_stack() {
+        let db = TempDb::new("handler-dual-stack");
+        dual_stack(Arc::new(db.store().unwrap()));
+    }
+
+    #[test]
+    fn memory_swarm_counts() {
+        swarm_counts(Arc::new(MemoryStore::new(4)));
+    }
+
+    #[test]
+    fn sqlite_swarm_counts() {
+        let db = TempDb::new("handler-counts");
+        swarm_counts(Arc::new(db.store().unwrap()));
     }
 }
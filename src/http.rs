@@ -0,0 +1,650 @@
+//  rtracker: bittorrent tracker
+//  Copyright (C) 2019  Justin Noah <justinnoah@gmail.com>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License.
+//
+//  This program is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//   GNU Affero General Public License for more details.
+//
+//   You should have received a copy of the GNU Affero General Public License
+//   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// HTTP announce and scrape, BEP 3 / BEP 23 (compact peers) / BEP 7 (peers6) / BEP 48 (scrape)
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use bencode::{self, Bytes};
+use handler::{self, canonical_addr, process_announce, Tracker};
+use packet_data_types::{Event, ScrapeStats, TrackerError};
+use parse_packets::MAX_SCRAPE_HASHES;
+use stats::incr;
+use storage::{Announce, Family, InfoHash, Peer, PeerId};
+
+// Requests are a single GET line and a few headers, anything bigger is not a tracker client
+const MAX_REQUEST_SIZE: usize = 8192;
+const MAX_CONNECTIONS: usize = 256;
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+const TEXT: &str = "text/plain";
+
+enum Failure {
+    /// Reported to the client as a bencoded failure reason
+    Client(TrackerError),
+    /// Our fault, answered with a 500
+    Server,
+}
+
+impl From<TrackerError> for Failure {
+    fn from(e: TrackerError) -> Failure {
+        Failure::Client(e)
+    }
+}
+
+/// A parsed request head
+pub struct Request {
+    pub method:  String,
+    pub path:    String,
+    pub query:   Vec<(String, Vec<u8>)>,
+    // Names in lower case
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn get(&self, key: &str) -> Option<&[u8]> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| &v[..])
+    }
+
+    fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.query
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| &v[..])
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(|v| ::std::str::from_utf8(v).ok())
+    }
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+// info_hash and peer_id are raw binary, so this decodes to bytes rather than a String
+pub fn percent_decode(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                    (Some(h), Some(l)) => {
+                        out.push(h << 4 | l);
+                        i += 3;
+                        continue;
+                    }
+                    _ => out.push(b'%'),
+                }
+            }
+            b'+' => out.push(b' '),
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    out
+}
+
+pub fn parse_request(head: &str) -> Option<Request> {
+    let mut lines = head.lines();
+    let mut parts = lines.next()?.split(' ');
+    let method = parts.next()?;
+    let target = parts.next()?;
+
+    let (path, query) = match target.find('?') {
+        Some(i) => (&target[..i], &target[i + 1..]),
+        None => (target, ""),
+    };
+
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.find('=') {
+            Some(i) => (
+                String::from_utf8_lossy(&percent_decode(&pair[..i])).into_owned(),
+                percent_decode(&pair[i + 1..]),
+            ),
+            None => (
+                String::from_utf8_lossy(&percent_decode(pair)).into_owned(),
+                Vec::new(),
+            ),
+        })
+        .collect();
+
+    let headers = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| {
+            let i = line.find(':')?;
+            Some((line[..i].trim().to_ascii_lowercase(), line[i + 1..].trim().to_string()))
+        })
+        .collect();
+
+    Some(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        headers,
+    })
+}
+
+#[derive(Serialize)]
+struct FailureResponse<'a> {
+    #[serde(rename = "failure reason")]
+    reason: &'a str,
+}
+
+#[derive(Serialize)]
+struct AnnounceResponse<'a> {
+    complete:     i32,
+    incomplete:   i32,
+    interval:     u32,
+    #[serde(rename = "min interval")]
+    min_interval: u32,
+    /// Compact IPv4 peers, or the peer dictionaries of a client that asked for them
+    peers:        Option<Peers<'a>>,
+    /// Compact IPv6 peers (BEP 7)
+    peers6:       Option<Bytes<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Peers<'a> {
+    Compact(Bytes<'a>),
+    Dicts(Vec<PeerDict<'a>>),
+}
+
+#[derive(Serialize)]
+struct PeerDict<'a> {
+    ip:      String,
+    port:    u16,
+    #[serde(rename = "peer id")]
+    peer_id: Option<Bytes<'a>>,
+}
+
+#[derive(Serialize)]
+struct ScrapeResponse<'a> {
+    files: BTreeMap<Bytes<'a>, ScrapeFile>,
+}
+
+#[derive(Serialize)]
+struct ScrapeFile {
+    complete:   i32,
+    downloaded: i32,
+    incomplete: i32,
+    /// BEP 48's optional name, known for torrents loaded from .torrent files
+    name:       Option<String>,
+}
+
+fn failure(reason: &str) -> Vec<u8> {
+    // A struct of one string always has a bencode form
+    bencode::to_bytes(&FailureResponse { reason }).unwrap_or_default()
+}
+
+// A response that can not be encoded is a bug of ours
+fn encode<T: Serialize>(response: &T) -> Result<Vec<u8>, Failure> {
+    bencode::to_bytes(response).map_err(|e| {
+        warn!("Failed to encode an HTTP response: {}", e);
+        Failure::Server
+    })
+}
+
+fn twenty_bytes(value: Option<&[u8]>) -> Option<[u8; 20]> {
+    let value = value?;
+    if value.len() != 20 {
+        return None;
+    }
+    let mut out = [0u8; 20];
+    out.copy_from_slice(value);
+    Some(out)
+}
+
+fn announce(req: &Request, src: SocketAddr, tracker: &Tracker) -> Result<Vec<u8>, Failure> {
+    let info_hash: InfoHash =
+        twenty_bytes(req.get("info_hash")).ok_or(TrackerError::MalformedRequest)?;
+    let peer_id: PeerId = twenty_bytes(req.get("peer_id")).ok_or(TrackerError::MalformedRequest)?;
+    let settings = tracker.settings();
+    if !settings.access.allowed(&info_hash) {
+        return Err(TrackerError::TorrentNotAllowed.into());
+    }
+    let port: u16 = req
+        .get_str("port")
+        .and_then(|p| p.parse().ok())
+        .ok_or(TrackerError::MalformedRequest)?;
+    let remaining: i64 = req
+        .get_str("left")
+        .and_then(|l| l.parse().ok())
+        .ok_or(TrackerError::MalformedRequest)?;
+    let event = match req.get_str("event") {
+        Some("completed") => Event::Completed,
+        Some("started") => Event::Started,
+        Some("stopped") => Event::Stopped,
+        _ => Event::None,
+    };
+    let num_want: i32 = req
+        .get_str("numwant")
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(-1);
+    // BEP 23: compact unless the client explicitly asks otherwise
+    let compact = req.get_str("compact") != Some("0");
+    let no_peer_id = req.get_str("no_peer_id") == Some("1");
+
+    // Like the UDP frontend, peers are taken at their source address
+    let announce = Announce {
+        info_hash,
+        peer_id,
+        addr: SocketAddr::new(src.ip(), port),
+        remaining,
+        event,
+    };
+    let family = Family::of(&src.ip());
+    let (swarm, counts) = match process_announce(tracker, &settings, &announce, num_want, family) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("HTTP announce from {} failed: {}", src, e);
+            return Err(Failure::Server);
+        }
+    };
+
+    let compact_swarm = compact_peers(&swarm);
+    let mut response = AnnounceResponse {
+        complete:     counts.seeders,
+        incomplete:   counts.leechers,
+        interval:     settings.config.announce_interval,
+        min_interval: settings.config.min_interval,
+        peers:        None,
+        peers6:       None,
+    };
+    if compact {
+        match family {
+            Family::V4 => response.peers = Some(Peers::Compact(Bytes(&compact_swarm))),
+            Family::V6 => response.peers6 = Some(Bytes(&compact_swarm)),
+        }
+    } else {
+        let peers = swarm
+            .iter()
+            .map(|peer| PeerDict {
+                ip:      peer.addr.ip().to_string(),
+                port:    peer.addr.port(),
+                peer_id: if no_peer_id { None } else { Some(Bytes(&peer.peer_id[..])) },
+            })
+            .collect();
+        response.peers = Some(Peers::Dicts(peers));
+    }
+
+    encode(&response)
+}
+
+fn compact_peers(swarm: &[Peer]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(swarm.len() * 18);
+    for peer in swarm {
+        match peer.addr.ip() {
+            IpAddr::V4(ip4) => out.extend_from_slice(&ip4.octets()),
+            IpAddr::V6(ip6) => out.extend_from_slice(&ip6.octets()),
+        }
+        out.extend_from_slice(&peer.addr.port().to_be_bytes());
+    }
+    out
+}
+
+fn scrape(req: &Request, tracker: &Tracker) -> Result<Vec<u8>, Failure> {
+    let mut hashes: Vec<InfoHash> = Vec::new();
+    for value in req.get_all("info_hash").take(MAX_SCRAPE_HASHES) {
+        hashes.push(twenty_bytes(Some(value)).ok_or(TrackerError::MalformedRequest)?);
+    }
+    if hashes.is_empty() {
+        return Err(TrackerError::MalformedRequest.into());
+    }
+    let settings = tracker.settings();
+    if !hashes.iter().all(|h| settings.access.allowed(h)) {
+        return Err(TrackerError::TorrentNotAllowed.into());
+    }
+
+    let stats: Vec<ScrapeStats> = match tracker.store.scrape(&hashes) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("HTTP scrape failed: {}", e);
+            return Err(Failure::Server);
+        }
+    };
+
+    // The same hash may have been asked for twice, the dictionary keeps one
+    let files = hashes
+        .iter()
+        .zip(stats)
+        .map(|(hash, stat)| {
+            let name = match tracker.store.name(hash) {
+                Ok(name) => name,
+                Err(e) => {
+                    debug!("No name for a scraped torrent: {}", e);
+                    None
+                }
+            };
+            let file = ScrapeFile {
+                complete: stat.seeders,
+                downloaded: stat.completed,
+                incomplete: stat.leechers,
+                name,
+            };
+            (Bytes(&hash[..]), file)
+        })
+        .collect();
+
+    encode(&ScrapeResponse { files })
+}
+
+pub fn write_response(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    let sent = stream
+        .write_all(head.as_bytes())
+        .and_then(|_| stream.write_all(body));
+    if let Err(e) = sent {
+        debug!("Failed to write HTTP response: {}", e);
+    }
+}
+
+// Read until the end of the request head
+pub fn read_head(stream: &mut TcpStream) -> Option<String> {
+    let mut buf = [0u8; 1024];
+    let mut head: Vec<u8> = Vec::new();
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let amt = stream.read(&mut buf).ok()?;
+        if amt == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..amt]);
+        if head.len() > MAX_REQUEST_SIZE {
+            return None;
+        }
+    }
+    String::from_utf8(head).ok()
+}
+
+fn handle_connection(mut stream: TcpStream, src: SocketAddr, tracker: &Tracker) {
+    let _ = stream.set_read_timeout(Some(TIMEOUT));
+    let _ = stream.set_write_timeout(Some(TIMEOUT));
+
+    let req = match read_head(&mut stream).as_ref().and_then(|h| parse_request(h)) {
+        // Announces and scrapes are only ever GETs
+        Some(x) if x.method == "GET" => x,
+        _ => {
+            incr(&tracker.stats.parse_errors);
+            write_response(&mut stream, "400 Bad Request", TEXT, b"");
+            return;
+        }
+    };
+    debug!("HTTP {} from {}", req.path, src);
+
+    let result = match req.path.as_str() {
+        "/announce" => announce(&req, src, tracker),
+        "/scrape" => scrape(&req, tracker),
+        _ => {
+            write_response(&mut stream, "404 Not Found", TEXT, b"");
+            return;
+        }
+    };
+
+    // Trackers report failures inside a successful response
+    match result {
+        Ok(body) => write_response(&mut stream, "200 OK", TEXT, &body),
+        Err(Failure::Client(e)) => {
+            incr(&tracker.stats.error_responses);
+            write_response(&mut stream, "200 OK", TEXT, &failure(e.message()));
+        }
+        Err(Failure::Server) => write_response(&mut stream, "500 Internal Server Error", TEXT, b""),
+    }
+}
+
+/// Serve HTTP announces and scrapes on `listener` until the process exits
+pub fn serve(listener: TcpListener, tracker: Arc<Tracker>) {
+    let active = Arc::new(AtomicUsize::new(0));
+    while let Some(accepted) = handler::accept(&listener, &tracker) {
+        let (stream, src) = match accepted {
+            Ok((stream, src)) => (stream, canonical_addr(src)),
+            Err(e) => {
+                warn!("Failed to accept an HTTP connection: {}", e);
+                continue;
+            }
+        };
+
+        if tracker.settings().blocklist.contains(src.ip()) {
+            debug!("Dropping an HTTP connection from blocked {}", src);
+            incr(&tracker.stats.blocked);
+            continue;
+        }
+        if active.load(Ordering::Relaxed) >= MAX_CONNECTIONS {
+            debug!("Too many HTTP connections, dropping {}", src);
+            incr(&tracker.stats.dropped_packets);
+            continue;
+        }
+
+        active.fetch_add(1, Ordering::Relaxed);
+        let tracker = tracker.clone();
+        let active = active.clone();
+        thread::spawn(move || {
+            handle_connection(stream, src, &tracker);
+            active.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+
+    // Announces in flight reach the store before it is flushed, each is bounded by TIMEOUT
+    while active.load(Ordering::Relaxed) > 0 {
+        thread::sleep(handler::ACCEPT_POLL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::mpsc;
+    use std::sync::Arc;
+
+    use super::*;
+    use bencode::from_bytes;
+    use database::tests::TempDb;
+    use handler::tests::tracker;
+    use memory::MemoryStore;
+    use storage::PeerStore;
+
+    #[derive(Deserialize)]
+    struct CompactAnswer {
+        peers:  Option<Vec<u8>>,
+        peers6: Option<Vec<u8>>,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct PeerAnswer {
+        ip:      String,
+        port:    u16,
+        #[serde(rename = "peer id")]
+        peer_id: Option<Vec<u8>>,
+    }
+
+    #[derive(Deserialize)]
+    struct DictAnswer {
+        complete:     i64,
+        incomplete:   i64,
+        interval:     i64,
+        #[serde(rename = "min interval")]
+        min_interval: i64,
+        peers:        Vec<PeerAnswer>,
+    }
+
+    fn escape(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("%{:02x}", b)).collect()
+    }
+
+    // The compact peers under `key` of the answer to a leecher at src
+    fn announce_peers(tracker: &Tracker, src: SocketAddr, peer_id: u8, key: &str) -> Vec<u8> {
+        let head = format!(
+            "GET /announce?info_hash={}&peer_id={}&port={}&left=10 HTTP/1.1\r\n\r\n",
+            escape(&[1; 20]),
+            escape(&[peer_id; 20]),
+            src.port()
+        );
+        let req = parse_request(&head).unwrap();
+        let body = match announce(&req, src, tracker) {
+            Ok(x) => x,
+            Err(_) => panic!("announce from {} failed", src),
+        };
+        let response: CompactAnswer = from_bytes(&body).unwrap();
+        let (wanted, other) = match key {
+            "peers" => (response.peers, response.peers6),
+            _ => (response.peers6, response.peers),
+        };
+        assert!(other.is_none());
+        wanted.unwrap()
+    }
+
+    fn dual_stack(store: Arc<dyn PeerStore>) {
+        let tracker = tracker(store);
+        let v4 = |i: u8| SocketAddr::from(([10, 0, 0, i], 6881));
+        let v6 = |i: u16| SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, i], 6881));
+        announce_peers(&tracker, v4(1), 1, "peers");
+        announce_peers(&tracker, v6(1), 2, "peers6");
+
+        let peers = announce_peers(&tracker, v4(2), 3, "peers");
+        assert_eq!(peers, [10, 0, 0, 1, 0x1a, 0xe1]);
+        let peers = announce_peers(&tracker, v6(2), 4, "peers6");
+        assert_eq!(peers.len(), 18);
+        assert_eq!(peers[..4], [0x20, 0x01, 0x0d, 0xb8]);
+        assert_eq!(peers[15..], [1, 0x1a, 0xe1]);
+    }
+
+    #[test]
+    fn memory_dual_stack() {
+        dual_stack(Arc::new(MemoryStore::new(4)));
+    }
+
+    #[test]
+    fn sqlite_dual_stack() {
+        let db = TempDb::new("http-dual-stack");
+        dual_stack(Arc::new(db.store().unwrap()));
+    }
+
+    #[test]
+    fn serve_returns_once_stopping() {
+        let tracker = Arc::new(tracker(Arc::new(MemoryStore::new(4))));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (done, finished) = mpsc::channel();
+        let serve_tracker = tracker.clone();
+        thread::spawn(move || {
+            serve(listener, serve_tracker);
+            done.send(()).unwrap();
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        let head = format!(
+            "GET /announce?info_hash={}&peer_id={}&port=6881&left=0 HTTP/1.1\r\n\r\n",
+            escape(&[1; 20]),
+            escape(&[2; 20])
+        );
+        client.write_all(head.as_bytes()).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+
+        tracker.stopping.store(true, Ordering::Relaxed);
+        assert!(finished.recv_timeout(Duration::from_secs(2)).is_ok());
+    }
+
+    #[test]
+    fn peer_dictionaries() {
+        let tracker = tracker(Arc::new(MemoryStore::new(4)));
+        let other = SocketAddr::from(([10, 0, 0, 1], 6881));
+        announce_peers(&tracker, other, 1, "peers");
+        let src = SocketAddr::from(([10, 0, 0, 2], 6882));
+        let query = format!(
+            "info_hash={}&peer_id={}&port=6882&left=10&compact=0",
+            escape(&[1; 20]),
+            escape(&[2; 20])
+        );
+        let answer = |extra: &str| -> DictAnswer {
+            let head = format!("GET /announce?{}{} HTTP/1.1\r\n\r\n", query, extra);
+            let body = announce(&parse_request(&head).unwrap(), src, &tracker).ok().unwrap();
+            from_bytes(&body).unwrap()
+        };
+
+        let response = answer("");
+        assert_eq!((response.complete, response.incomplete), (0, 2));
+        assert_eq!((response.interval, response.min_interval), (1800, 900));
+        let mut peer = PeerAnswer {
+            ip:      String::from("10.0.0.1"),
+            port:    6881,
+            peer_id: Some(vec![1; 20]),
+        };
+        assert_eq!(response.peers, vec![peer]);
+        peer = PeerAnswer {
+            ip:      String::from("10.0.0.1"),
+            port:    6881,
+            peer_id: None,
+        };
+        assert_eq!(answer("&no_peer_id=1").peers, vec![peer]);
+    }
+
+    #[test]
+    fn scrape_dictionary() {
+        let tracker = tracker(Arc::new(MemoryStore::new(4)));
+        announce_peers(&tracker, SocketAddr::from(([10, 0, 0, 1], 6881)), 1, "peers");
+        let head = format!(
+            "GET /scrape?info_hash={}&info_hash={} HTTP/1.1\r\n\r\n",
+            escape(&[2; 20]),
+            escape(&[1; 20])
+        );
+        let body = scrape(&parse_request(&head).unwrap(), &tracker).ok().unwrap();
+        let mut expected = b"d5:filesd20:".to_vec();
+        expected.extend_from_slice(&[1; 20]);
+        expected.extend_from_slice(b"d8:completei0e10:downloadedi0e10:incompletei1ee20:");
+        expected.extend_from_slice(&[2; 20]);
+        expected.extend_from_slice(b"d8:completei0e10:downloadedi0e10:incompletei0eeee");
+        assert_eq!(body, expected);
+    }
+
+    #[test]
+    fn failure_reason() {
+        assert_eq!(failure("no such torrent"), b"d14:failure reason15:no such torrente".to_vec());
+    }
+}
@@ -17,6 +17,7 @@ extern crate bincode;
 extern crate chrono;
 extern crate docopt;
 extern crate env_logger;
+extern crate hmac;
 extern crate ini;
 #[macro_use]
 extern crate log;
@@ -27,22 +28,58 @@ extern crate rusqlite;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
+extern crate sha1;
+extern crate sha2;
+extern crate signal_hook;
+#[cfg(target_os = "linux")]
+extern crate libc;
+#[cfg(target_os = "linux")]
+extern crate socket2;
 
-use std::net::UdpSocket;
+use std::net::{SocketAddr, TcpListener};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use docopt::Docopt;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+#[cfg(unix)]
+use signal_hook::{consts::SIGHUP, iterator::Signals};
 
-use config::ServerConfig;
-use database::{db_connection_pool, db_init, db_prune};
-use handler::handle_received_packet;
+use config::{Backend, ServerConfig};
+use connection_id::ConnectionIds;
+use database::{db_connection_pool, SqliteStore};
+use handler::Tracker;
+use memory::MemoryStore;
+use ratelimit::RateLimiter;
+use stats::Stats;
+use storage::PeerStore;
 
+mod access;
+mod admin;
+#[cfg(target_os = "linux")]
+mod batch;
+mod bencode;
+mod blocklist;
 mod config;
+mod connection_id;
 mod database;
 mod handler;
+mod http;
+mod memory;
+mod metrics;
 mod packet_data_types;
 mod parse_packets;
+mod ratelimit;
+mod reload;
+mod sockets;
+mod stats;
+mod storage;
+mod torrents;
 
 static USAGE: &str = "
 Usage: rtracker [-c <conf>]
@@ -67,52 +104,234 @@ fn main() {
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
 
-    let scfg = ServerConfig::new(&args.flag_conf);
+    let scfg = match ServerConfig::new(&args.flag_conf) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
     debug!("addr: {:?}", scfg.address);
 
     // Initialize the database.
-    let sock = match UdpSocket::bind(&scfg.address) {
+    let socks = match sockets::bind_udp(scfg.address, scfg.sockets) {
         Ok(s) => s,
         Err(e) => panic!("{}", e),
     };
+    // Workers wake up now and then to see whether they should stop
+    for sock in &socks {
+        if let Err(e) = sock.set_read_timeout(Some(handler::STOP_POLL)) {
+            panic!("{}", e);
+        }
+    }
 
     info!("Listening on: {}", &scfg.address);
-    let pool = db_connection_pool(scfg.pool_size);
-    db_init(pool.get().unwrap());
-    debug!("DB initialized");
+    if scfg.backend == Backend::Sqlite && scfg.workers > scfg.pool_size {
+        warn!(
+            "{} workers share {} database connections, raise thread_pool_size to match",
+            scfg.workers, scfg.pool_size
+        );
+    }
+    if cfg!(not(target_os = "linux")) && scfg.sockets > 1 {
+        warn!("sockets needs SO_REUSEPORT on Linux, binding one socket");
+    }
+    if cfg!(not(target_os = "linux")) && scfg.batch_size > 1 {
+        warn!("batch_size needs recvmmsg on Linux, reading one packet at a time");
+    }
+    // Counters exist before the store, the pool times its checkouts into them
+    let stats = Stats::default();
+    // The SQLite pool also holds the access_list table
+    let mut db_pool = None;
+    let store: Arc<dyn PeerStore> = match scfg.backend {
+        Backend::Sqlite => {
+            let wait = stats.pool_wait.clone();
+            let store = db_connection_pool(scfg.pool_size, scfg.db_path.as_deref(), wait)
+                .and_then(|pool| SqliteStore::new(pool.clone()).map(|s| (s, pool)));
+            match store {
+                Ok((x, pool)) => {
+                    db_pool = Some(pool);
+                    Arc::new(x)
+                }
+                Err(e) => panic!("Failed to open the database: {}", e),
+            }
+        }
+        Backend::Memory => Arc::new(MemoryStore::new(scfg.shards)),
+    };
+
+    let http_address = scfg.http_address;
+    let metrics_address = scfg.metrics_address;
+    let admin_address = scfg.admin_address;
+    let limiter = RateLimiter::new(scfg.ip_limit, scfg.prefix_limit, scfg.global_limit);
+    let mut torrent_dir = None;
+    let settings = match reload::load_settings(scfg, db_pool.as_ref(), &*store, &mut torrent_dir) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    let tracker = Arc::new(Tracker {
+        settings: RwLock::new(Arc::new(settings)),
+        store,
+        // Connection IDs are checked statelessly against this secret
+        ids: ConnectionIds::new(),
+        stats,
+        limiter,
+        started: Instant::now(),
+        config_path: args.flag_conf,
+        db_pool,
+        torrent_dir: Mutex::new(torrent_dir),
+        stopping: Arc::new(AtomicBool::new(false)),
+        prune_lock: Mutex::new(()),
+    });
+
+    // A first SIGINT or SIGTERM stops the tracker cleanly, a second one exits at once
+    for &signal in &[SIGINT, SIGTERM] {
+        let registered = flag::register_conditional_shutdown(signal, 1, tracker.stopping.clone())
+            .and_then(|_| flag::register(signal, tracker.stopping.clone()));
+        if let Err(e) = registered {
+            panic!("{}", e);
+        }
+    }
+
+    // SIGHUP applies the config file again, as far as it can be without a restart
+    #[cfg(unix)]
+    {
+        let mut signals = match Signals::new([SIGHUP]) {
+            Ok(s) => s,
+            Err(e) => panic!("{}", e),
+        };
+        let hup_tracker = tracker.clone();
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                info!("SIGHUP, reloading the config");
+                // reload() logs why a config was not applied
+                let _ = reload::reload(&hup_tracker);
+            }
+        });
+    }
 
     // Spawn the database pruning thread
-    let prune_pool = pool.clone();
+    let prune_tracker = tracker.clone();
     thread::spawn(move || {
         loop {
-            // Every minute run the prune function.
-            // As of this comment, db_prune selects all torrents / connections with a (now -
-            // last_active) > 30 minutes. Thus, the 30 minute prune has a polling resolution of one
-            // minute.
-            let prune_delay = Duration::new(60u64, 0);
+            // Every prune_period run the prune function.
+            // db_prune selects all torrents / connections with a (now - last_active) >
+            // peer_timeout. Thus, the timeout has a polling resolution of prune_period.
+            let prune_period = prune_tracker.settings().config.prune_period;
+            let prune_delay = Duration::new(u64::from(prune_period), 0);
             thread::sleep(prune_delay);
+            if prune_tracker.is_stopping() {
+                break;
+            }
             debug!("Prune the database!");
             // Prune the database
-            let prune_conn = prune_pool.get().unwrap();
-            db_prune(prune_conn);
+            match prune_tracker.prune() {
+                Ok(x) => debug!("Pruned {} peers", x),
+                Err(e) => warn!("Prune failed: {}", e),
+            }
+            // Rate limits of clients that went quiet
+            debug!("Pruned {} rate limit buckets", prune_tracker.limiter.prune());
         }
     });
 
-    loop {
-        // This will become flexible. Simply a starting point
-        debug!("Init udp packet buffer");
-        // UDP packet max
-        let mut buf = [0u8; 1500];
-        debug!("IOWait");
-        let (amt, src) = sock.recv_from(&mut buf).unwrap();
-        let tsock = sock.try_clone().unwrap();
-        let tpool = pool.clone();
-        if amt >= 16 {
-            let mut packet: Vec<u8> = buf.to_vec();
-            packet.resize(amt, 0);
-            handle_received_packet(packet, src, tsock, tpool.get().unwrap());
-        } else {
-            debug!("Received a tiny packet (size: {}), ignoring", amt)
-        }
+    // Pick up access list changes without a restart, a reload may turn the list on
+    let refresh_tracker = tracker.clone();
+    thread::spawn(move || loop {
+        let period = refresh_tracker.settings().config.access_reload_period;
+        thread::sleep(Duration::new(u64::from(period), 0));
+        reload::refresh_access(&refresh_tracker);
+    });
+
+    // Serve HTTP clients from their own thread
+    let mut listeners = Vec::new();
+    if let Some(addr) = http_address {
+        let listener = listen(addr);
+        info!("HTTP listening on: {}", addr);
+        let http_tracker = tracker.clone();
+        listeners.push(thread::spawn(move || http::serve(listener, http_tracker)));
+    }
+
+    // Metrics get a listener of their own, usually kept off the public address
+    if let Some(addr) = metrics_address {
+        let listener = listen(addr);
+        info!("Metrics on: http://{}/metrics", addr);
+        let metrics_tracker = tracker.clone();
+        listeners.push(thread::spawn(move || metrics::serve(listener, metrics_tracker)));
+    }
+
+    if let Some(addr) = admin_address {
+        let listener = listen(addr);
+        info!("Admin API on: http://{}/", addr);
+        let admin_tracker = tracker.clone();
+        listeners.push(thread::spawn(move || admin::serve(listener, admin_tracker)));
+    }
+
+    // Every worker reads a socket itself through its own handle, so a burst queues in the
+    // kernel's receive buffer and what does not fit there is dropped by the kernel.
+    // Workers are dealt out over the sockets in turn.
+    let workers: Vec<thread::JoinHandle<()>> = (0..tracker.settings().config.workers)
+        .map(|i| {
+            let worker_sock = match socks[i % socks.len()].try_clone() {
+                Ok(s) => s,
+                Err(e) => panic!("{}", e),
+            };
+            let worker_tracker = tracker.clone();
+            thread::Builder::new()
+                .name(format!("worker-{}", i))
+                .spawn(move || handler::serve(worker_sock, worker_tracker))
+                .unwrap()
+        })
+        .collect();
+    info!("{} workers answering UDP on {} sockets", workers.len(), socks.len());
+    for worker in workers.into_iter().chain(listeners) {
+        let _ = worker.join();
+    }
+
+    // Every packet and HTTP request in hand is answered, nothing else reaches the store
+    info!("Stopping");
+    let flushed = tracker.flush();
+    log_summary(&tracker);
+    if let Err(e) = flushed {
+        error!("Failed to flush the store: {}", e);
+        process::exit(1);
+    }
+}
+
+/// A listener for one of the TCP services, non-blocking so it notices the tracker stopping
+fn listen(addr: SocketAddr) -> TcpListener {
+    let listener = match TcpListener::bind(addr) {
+        Ok(l) => l,
+        Err(e) => panic!("{}", e),
+    };
+    if let Err(e) = listener.set_nonblocking(true) {
+        panic!("{}", e);
+    }
+    listener
+}
+
+fn log_summary(tracker: &Tracker) {
+    let stats = &tracker.stats;
+    let count = |c: &AtomicU64| c.load(Ordering::Relaxed);
+    let answered = [&stats.connect, &stats.announce, &stats.scrape]
+        .iter()
+        .map(|a| count(&a.answered))
+        .sum::<u64>();
+    info!(
+        "Up {}s: {} packets, {} answered, {} errors, {} dropped, {} blocked, {} rate limited",
+        tracker.started.elapsed().as_secs(),
+        count(&stats.packets_received),
+        answered,
+        count(&stats.error_responses),
+        count(&stats.dropped_packets),
+        count(&stats.blocked),
+        count(&stats.rate_limited)
+    );
+    match tracker.store.totals() {
+        Ok(t) => info!(
+            "Leaving {} torrents with {} seeders and {} leechers",
+            t.torrents, t.seeders, t.leechers
+        ),
+        Err(e) => warn!("Failed to count the swarms: {}", e),
     }
 }
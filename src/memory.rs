@@ -0,0 +1,273 @@
+//  rtracker: bittorrent tracker
+//  Copyright (C) 2019  Justin Noah <justinnoah@gmail.com>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License.
+//
+//  This program is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//   GNU Affero General Public License for more details.
+//
+//   You should have received a copy of the GNU Affero General Public License
+//   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use chrono::prelude::Utc;
+
+use packet_data_types::{Event, ScrapeStats};
+use storage::{Announce, Family, InfoHash, Peer, PeerId, PeerStore, StoreError, Totals};
+
+#[derive(Debug)]
+struct PeerEntry {
+    peer_id:     PeerId,
+    remaining:   i64,
+    last_active: i64,
+    started:     Option<i64>,
+}
+
+impl PeerEntry {
+    fn is_seeder(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+// A peer is its address, a client restarting with a new peer_id replaces itself
+#[derive(Debug, Default)]
+struct Swarm {
+    peers:     HashMap<SocketAddr, PeerEntry>,
+    // Kept in step with peers on every change
+    seeders:   i32,
+    leechers:  i32,
+    // Survives pruning, unlike the peers
+    completed: i32,
+}
+
+impl Swarm {
+    fn count(&mut self, entry: &PeerEntry, delta: i32) {
+        if entry.is_seeder() {
+            self.seeders += delta;
+        } else {
+            self.leechers += delta;
+        }
+    }
+
+    fn insert(&mut self, addr: SocketAddr, entry: PeerEntry) {
+        self.count(&entry, 1);
+        if let Some(old) = self.peers.insert(addr, entry) {
+            self.count(&old, -1);
+        }
+    }
+
+    fn remove(&mut self, addr: &SocketAddr) -> Option<PeerEntry> {
+        let old = self.peers.remove(addr);
+        if let Some(ref x) = old {
+            self.count(x, -1);
+        }
+        old
+    }
+}
+
+type Shard = HashMap<InfoHash, Swarm>;
+
+/// Peers kept in a `HashMap` split over many locks, so announces for different torrents rarely
+/// wait on each other. Nothing survives a restart.
+pub struct MemoryStore {
+    shards: Vec<RwLock<Shard>>,
+    // Few and rarely written, one lock is plenty
+    names:  RwLock<HashMap<InfoHash, String>>,
+}
+
+impl MemoryStore {
+    pub fn new(shards: usize) -> MemoryStore {
+        let shards = shards.max(1);
+        debug!("{} memory store shards", shards);
+        MemoryStore {
+            shards: (0..shards).map(|_| RwLock::new(HashMap::new())).collect(),
+            names: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn shard_index(&self, info_hash: &InfoHash) -> usize {
+        // Info hashes are SHA-1 digests, so any few bytes are already well spread
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&info_hash[..8]);
+        (u64::from_le_bytes(bytes) % self.shards.len() as u64) as usize
+    }
+
+    // A poisoned lock only means another thread panicked mid-update, the map is still usable
+    fn read(&self, info_hash: &InfoHash) -> RwLockReadGuard<'_, Shard> {
+        let shard = &self.shards[self.shard_index(info_hash)];
+        shard.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self, info_hash: &InfoHash) -> RwLockWriteGuard<'_, Shard> {
+        let shard = &self.shards[self.shard_index(info_hash)];
+        shard.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl PeerStore for MemoryStore {
+    fn announce(&self, announce: &Announce) -> Result<(), StoreError> {
+        let now = Utc::now().timestamp();
+        let mut shard = self.write(&announce.info_hash);
+
+        let swarm = shard.entry(announce.info_hash).or_default();
+        let started = if announce.event == Event::Started {
+            Some(now)
+        } else {
+            swarm.peers.get(&announce.addr).and_then(|p| p.started)
+        };
+        swarm.insert(
+            announce.addr,
+            PeerEntry {
+                peer_id: announce.peer_id,
+                remaining: announce.remaining,
+                last_active: now,
+                started,
+            },
+        );
+
+        if announce.event == Event::Completed {
+            swarm.completed += 1;
+        }
+
+        Ok(())
+    }
+
+    fn peers(&self, info_hash: &InfoHash, family: Family) -> Result<Vec<Peer>, StoreError> {
+        let shard = self.read(info_hash);
+        let mut swarm: Vec<Peer> = match shard.get(info_hash) {
+            Some(s) => s
+                .peers
+                .iter()
+                .filter(|&(addr, _)| Family::of(&addr.ip()) == family)
+                .map(|(&addr, entry)| Peer {
+                    addr,
+                    peer_id: entry.peer_id,
+                    remaining: entry.remaining,
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+
+        // Seeders first
+        swarm.sort_by_key(|p| !p.is_seeder());
+        Ok(swarm)
+    }
+
+    fn counts(&self, info_hash: &InfoHash) -> Result<ScrapeStats, StoreError> {
+        let shard = self.read(info_hash);
+        Ok(match shard.get(info_hash) {
+            Some(swarm) => ScrapeStats {
+                seeders: swarm.seeders,
+                completed: swarm.completed,
+                leechers: swarm.leechers,
+            },
+            None => ScrapeStats::default(),
+        })
+    }
+
+    fn remove(
+        &self,
+        info_hash: &InfoHash,
+        addr: SocketAddr,
+        peer_id: &PeerId,
+    ) -> Result<bool, StoreError> {
+        let mut shard = self.write(info_hash);
+        Ok(match shard.get_mut(info_hash) {
+            // Only the peer at that address may remove itself
+            Some(swarm) => match swarm.peers.get(&addr) {
+                Some(entry) if entry.peer_id == *peer_id => swarm.remove(&addr).is_some(),
+                _ => false,
+            },
+            None => false,
+        })
+    }
+
+    fn remove_peer(&self, info_hash: &InfoHash, addr: SocketAddr) -> Result<bool, StoreError> {
+        let mut shard = self.write(info_hash);
+        Ok(match shard.get_mut(info_hash) {
+            Some(swarm) => swarm.remove(&addr).is_some(),
+            None => false,
+        })
+    }
+
+    fn remove_torrent(&self, info_hash: &InfoHash) -> Result<usize, StoreError> {
+        let mut shard = self.write(info_hash);
+        Ok(shard.remove(info_hash).map_or(0, |swarm| swarm.peers.len()))
+    }
+
+    fn top(&self, limit: usize) -> Result<Vec<(InfoHash, ScrapeStats)>, StoreError> {
+        let mut torrents: Vec<(InfoHash, ScrapeStats)> = Vec::new();
+        for shard in &self.shards {
+            let shard = shard.read().unwrap_or_else(|e| e.into_inner());
+            torrents.extend(shard.iter().filter(|(_, s)| !s.peers.is_empty()).map(|(h, s)| {
+                let counts = ScrapeStats {
+                    seeders: s.seeders,
+                    completed: s.completed,
+                    leechers: s.leechers,
+                };
+                (*h, counts)
+            }));
+        }
+        torrents.sort_by_key(|(_, c)| -(c.seeders + c.leechers));
+        torrents.truncate(limit);
+        Ok(torrents)
+    }
+
+    fn prune(&self, timeout: i64) -> Result<usize, StoreError> {
+        let now = Utc::now().timestamp();
+        let mut removed = 0;
+
+        // One shard at a time so announces elsewhere carry on
+        for shard in &self.shards {
+            let mut shard = shard.write().unwrap_or_else(|e| e.into_inner());
+            shard.retain(|_, swarm| {
+                let stale: Vec<SocketAddr> = swarm
+                    .peers
+                    .iter()
+                    .filter(|&(_, p)| now - p.last_active > timeout)
+                    .map(|(&addr, _)| addr)
+                    .collect();
+                for addr in &stale {
+                    swarm.remove(addr);
+                }
+                removed += stale.len();
+
+                // Forget torrents nobody is on, unless they have history worth keeping
+                !swarm.peers.is_empty() || swarm.completed > 0
+            });
+        }
+
+        Ok(removed)
+    }
+
+    fn totals(&self) -> Result<Totals, StoreError> {
+        let mut totals = Totals::default();
+        for shard in &self.shards {
+            let shard = shard.read().unwrap_or_else(|e| e.into_inner());
+            for swarm in shard.values().filter(|s| !s.peers.is_empty()) {
+                totals.torrents += 1;
+                totals.seeders += i64::from(swarm.seeders);
+                totals.leechers += i64::from(swarm.leechers);
+            }
+        }
+        Ok(totals)
+    }
+
+    fn set_name(&self, info_hash: &InfoHash, name: &str) -> Result<(), StoreError> {
+        let mut names = self.names.write().unwrap_or_else(|e| e.into_inner());
+        names.insert(*info_hash, name.to_string());
+        Ok(())
+    }
+
+    fn name(&self, info_hash: &InfoHash) -> Result<Option<String>, StoreError> {
+        let names = self.names.read().unwrap_or_else(|e| e.into_inner());
+        Ok(names.get(info_hash).cloned())
+    }
+}
@@ -0,0 +1,226 @@
+//  rtracker: bittorrent tracker
+//  Copyright (C) 2019  Justin Noah <justinnoah@gmail.com>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License.
+//
+//  This program is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//   GNU Affero General Public License for more details.
+//
+//   You should have received a copy of the GNU Affero General Public License
+//   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Prometheus text format metrics on GET /metrics
+
+use std::fmt::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use handler::{self, Tracker};
+use http::{parse_request, read_head, write_response, TIMEOUT};
+use stats::ActionStats;
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    header(out, name, "counter", help);
+    let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: i64) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Every metric of the tracker, as Prometheus scrapes them
+pub fn render(tracker: &Tracker) -> String {
+    let stats = &tracker.stats;
+    let mut out = String::new();
+    let actions: [(&str, &ActionStats); 3] = [
+        ("connect", &stats.connect),
+        ("announce", &stats.announce),
+        ("scrape", &stats.scrape),
+    ];
+
+    header(
+        &mut out,
+        "rtracker_packets_total",
+        "counter",
+        "UDP packets with a valid header, by action",
+    );
+    for (name, a) in &actions {
+        let received = a.received.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "rtracker_packets_total{{action=\"{}\"}} {}",
+            name, received
+        );
+    }
+    let unknown = stats.unknown_action.load(Ordering::Relaxed);
+    let _ = writeln!(
+        out,
+        "rtracker_packets_total{{action=\"unknown\"}} {}",
+        unknown
+    );
+
+    header(
+        &mut out,
+        "rtracker_responses_total",
+        "counter",
+        "Responses by type, errors include HTTP failure replies",
+    );
+    for (name, a) in &actions {
+        let answered = a.answered.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "rtracker_responses_total{{type=\"{}\"}} {}",
+            name, answered
+        );
+    }
+    let errors = stats.error_responses.load(Ordering::Relaxed);
+    let _ = writeln!(out, "rtracker_responses_total{{type=\"error\"}} {}", errors);
+
+    counter(
+        &mut out,
+        "rtracker_received_total",
+        "UDP packets long enough to parse",
+        &stats.packets_received,
+    );
+    counter(
+        &mut out,
+        "rtracker_parse_errors_total",
+        "Malformed packets and requests",
+        &stats.parse_errors,
+    );
+    counter(
+        &mut out,
+        "rtracker_dropped_total",
+        "Packets and connections left unanswered",
+        &stats.dropped_packets,
+    );
+    counter(
+        &mut out,
+        "rtracker_blocked_total",
+        "Packets and connections from blocklisted addresses",
+        &stats.blocked,
+    );
+    counter(
+        &mut out,
+        "rtracker_rate_limited_total",
+        "UDP packets over a rate limit",
+        &stats.rate_limited,
+    );
+
+    header(
+        &mut out,
+        "rtracker_handler_seconds",
+        "histogram",
+        "Time to answer a UDP packet, by action",
+    );
+    for (name, a) in &actions {
+        a.latency.render(
+            &mut out,
+            "rtracker_handler_seconds",
+            &format!("action=\"{}\"", name),
+        );
+    }
+
+    match tracker.store.totals() {
+        Ok(totals) => {
+            gauge(
+                &mut out,
+                "rtracker_torrents",
+                "Torrents with at least one peer",
+                totals.torrents,
+            );
+            gauge(
+                &mut out,
+                "rtracker_peers",
+                "Peers of every torrent",
+                totals.seeders + totals.leechers,
+            );
+            gauge(
+                &mut out,
+                "rtracker_seeders",
+                "Seeders of every torrent",
+                totals.seeders,
+            );
+            gauge(
+                &mut out,
+                "rtracker_leechers",
+                "Leechers of every torrent",
+                totals.leechers,
+            );
+        }
+        Err(e) => warn!("Failed to count the swarms for metrics: {}", e),
+    }
+
+    counter(
+        &mut out,
+        "rtracker_prunes_total",
+        "Prunes of stale peers",
+        &stats.prunes,
+    );
+    counter(
+        &mut out,
+        "rtracker_pruned_peers_total",
+        "Stale peers removed by prunes",
+        &stats.pruned_peers,
+    );
+    header(
+        &mut out,
+        "rtracker_prune_duration_seconds",
+        "gauge",
+        "How long the last prune took",
+    );
+    let prune = stats.prune_micros.load(Ordering::Relaxed) as f64 / 1e6;
+    let _ = writeln!(out, "rtracker_prune_duration_seconds {}", prune);
+
+    header(
+        &mut out,
+        "rtracker_pool_wait_seconds",
+        "histogram",
+        "Time spent waiting for a database connection",
+    );
+    stats
+        .pool_wait
+        .render(&mut out, "rtracker_pool_wait_seconds", "");
+
+    out
+}
+
+fn handle_connection(mut stream: TcpStream, tracker: &Tracker) {
+    let _ = stream.set_read_timeout(Some(TIMEOUT));
+    let _ = stream.set_write_timeout(Some(TIMEOUT));
+
+    match read_head(&mut stream)
+        .as_ref()
+        .and_then(|h| parse_request(h))
+    {
+        Some(ref req) if req.path == "/metrics" => {
+            let body = render(tracker);
+            write_response(&mut stream, "200 OK", CONTENT_TYPE, body.as_bytes());
+        }
+        Some(_) => write_response(&mut stream, "404 Not Found", CONTENT_TYPE, b""),
+        None => write_response(&mut stream, "400 Bad Request", CONTENT_TYPE, b""),
+    }
+}
+
+/// Answer metrics scrapes on `listener` one at a time until the process exits
+pub fn serve(listener: TcpListener, tracker: Arc<Tracker>) {
+    while let Some(accepted) = handler::accept(&listener, &tracker) {
+        match accepted {
+            Ok((stream, _)) => handle_connection(stream, &tracker),
+            Err(e) => warn!("Failed to accept a metrics connection: {}", e),
+        }
+    }
+}
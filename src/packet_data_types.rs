@@ -50,9 +50,64 @@ pub struct ClientAnnounce {
     pub port:       u16,      // 82
 }
 
+// Followed by the error message as raw bytes, running to the end of the packet
 #[derive(Debug, Serialize)]
 pub struct ServerError {
     pub action:         i32,
     pub transaction_id: i32,
-    pub error:          String,
+}
+
+/// Every error condition the tracker reports back to a client
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrackerError {
+    UnknownAction,
+    BadConnectionId,
+    TorrentNotAllowed,
+    RateLimited,
+    MalformedRequest,
+}
+
+impl TrackerError {
+    pub fn message(self) -> &'static str {
+        match self {
+            TrackerError::UnknownAction => "unknown action",
+            TrackerError::BadConnectionId => "bad connection id",
+            TrackerError::TorrentNotAllowed => "torrent not allowed on this tracker",
+            TrackerError::RateLimited => "rate limited, slow down",
+            TrackerError::MalformedRequest => "malformed request",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    None,
+    Completed,
+    Started,
+    Stopped,
+}
+
+impl Event {
+    // Unknown events are treated as a regular announce
+    pub fn from_i32(event: i32) -> Event {
+        match event {
+            1 => Event::Completed,
+            2 => Event::Started,
+            3 => Event::Stopped,
+            _ => Event::None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScrapeResponse {
+    pub action:         i32,
+    pub transaction_id: i32,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ScrapeStats {
+    pub seeders:        i32,
+    pub completed:      i32,
+    pub leechers:       i32,
 }
@@ -17,32 +17,77 @@
 //
 // NetworkEndian = Big Endian
 
-use std::net::IpAddr;
-use std::str::FromStr;
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
 
-use bincode::{Options, options, serialized_size};
+use bincode::{DefaultOptions, Options, options, serialized_size};
+use bincode::config::{
+    AllowTrailing, BigEndian, FixintEncoding, WithOtherEndian, WithOtherIntEncoding,
+    WithOtherTrailing,
+};
 
 use packet_data_types::*;
 
-pub fn parse_header(packet: &[u8]) -> PacketHeader {
+type WireOptions = WithOtherTrailing<
+    WithOtherEndian<WithOtherIntEncoding<DefaultOptions, FixintEncoding>, BigEndian>,
+    AllowTrailing,
+>;
+
+// Fixed width, Network Order integers as the wire protocol expects
+fn wire() -> WireOptions {
+    options().with_fixint_encoding().with_big_endian().allow_trailing_bytes()
+}
+
+pub const HEADER_SIZE: usize = 16;
+pub const ANNOUNCE_SIZE: usize = 82;
+
+#[derive(Debug)]
+pub enum ParseError {
+    /// The packet (or packet body) is shorter than the structure being decoded
+    TooShort { needed: usize, got: usize },
+    /// A scrape that does not contain a single info_hash
+    NoInfoHash,
+    Decode(bincode::Error),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::TooShort { needed, got } => {
+                write!(f, "needed {} bytes, got {}", needed, got)
+            }
+            ParseError::NoInfoHash => write!(f, "no info_hash given"),
+            ParseError::Decode(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<bincode::Error> for ParseError {
+    fn from(e: bincode::Error) -> ParseError {
+        ParseError::Decode(e)
+    }
+}
+
+fn check_len(packet: &[u8], needed: usize) -> Result<(), ParseError> {
+    if packet.len() < needed {
+        return Err(ParseError::TooShort {
+            needed,
+            got: packet.len(),
+        });
+    }
+    Ok(())
+}
+
+pub fn parse_header(packet: &[u8]) -> Result<PacketHeader, ParseError> {
     debug!("Deserializing header of len {:?}", packet.len());
+    check_len(packet, HEADER_SIZE)?;
 
     debug!("ID Bytes: {:?}", &packet[0..8]);
-    debug!("ID: {0:x}", {
-        bincode::deserialize::<i64>(&packet[0..8]).unwrap().to_be()
-    });
-
     debug!("Action Bytes: {:?}", &packet[8..12]);
-    debug!("Action: {:?}", {
-        bincode::deserialize::<i32>(&packet[8..12]).unwrap().to_be()
-    });
-
     debug!("TID Bytes: {:?}", &packet[12..16]);
-    debug!("TID: {:?}", {
-        bincode::deserialize::<i32>(&packet[12..16]).unwrap().to_be()
-    });
 
-    options().deserialize::<PacketHeader>(&packet[0..16]).unwrap()
+    Ok(wire().deserialize::<PacketHeader>(&packet[0..HEADER_SIZE])?)
 }
 
 pub fn encode_server_connect(uuid: i64, tran_id: i32) -> Vec<u8> {
@@ -53,18 +98,19 @@ pub fn encode_server_connect(uuid: i64, tran_id: i32) -> Vec<u8> {
     };
 
     // Network Order, Bounded(16)
-    let v: Vec<u8> = options().with_big_endian().with_limit(16).serialize(&packet).unwrap();
+    let v: Vec<u8> = wire().with_limit(16).serialize(&packet).unwrap();
 
     debug!("v: {:?}", v);
     v
 }
 
-pub fn decode_client_announce(packet: &[u8]) -> ClientAnnounce {
+pub fn decode_client_announce(packet: &[u8]) -> Result<ClientAnnounce, ParseError> {
     let ca = ClientAnnounce::default();
 
     debug!("ClientAnnounce serialized size: {:?}", serialized_size(&ca));
     debug!("Deserializing Client Announce!");
     debug!("packet len : {:?}", packet.len());
+    check_len(packet, ANNOUNCE_SIZE)?;
     debug!("info_hash  : {:?}", &packet[..20]);
     debug!("peer_id    : {:?}", &packet[20..40]);
     debug!("downloaded : {:?}", &packet[40..48]);
@@ -75,20 +121,17 @@ pub fn decode_client_announce(packet: &[u8]) -> ClientAnnounce {
     debug!("key        : {:?}", &packet[72..76]);
     debug!("num_want   : {:?}", &packet[76..80]);
     debug!("port       : {:?}", &packet[80..82]);
-    if packet.len() > 82 {
-        debug!("extensions : {:?}", &packet[82..]);
+    if packet.len() > ANNOUNCE_SIZE {
+        debug!("extensions : {:?}", &packet[ANNOUNCE_SIZE..]);
     }
 
-    match options().with_big_endian().deserialize(packet) {
-        Ok(x) => x,
-        Err(p) => panic!("{:?}", p),
-    }
+    Ok(wire().deserialize(packet)?)
 }
 
 pub fn encode_server_announce(
     transaction_id: i32,
-    mut swarm: Vec<(String, i32)>,
-    num_want: i32,
+    swarm: Vec<SocketAddr>,
+    interval: u32,
     leechers: i32,
     seeders: i32,
 ) -> Vec<u8> {
@@ -96,56 +139,118 @@ pub fn encode_server_announce(
         // Action for Announce is always 1
         action: 1,
         transaction_id,
-        // 30min in secs
-        interval: 1800,
+        // validate() keeps the interval in range, clamp rather than wrap should one slip by
+        interval: i32::try_from(interval).unwrap_or(i32::MAX),
         leechers,
         seeders,
     };
 
-    let mut packet = options().with_big_endian().serialize(&packet).unwrap();
+    let mut packet = wire().serialize(&packet).unwrap();
+
+    // Compact peers are 6 bytes for IPv4 and 18 bytes for IPv6, a client can only read one of
+    // the two so the swarm must be of a single family
+    for peer in swarm {
+        match peer.ip() {
+            IpAddr::V4(ip4) => packet.extend_from_slice(&ip4.octets()),
+            IpAddr::V6(ip6) => packet.extend_from_slice(&ip6.octets()),
+        }
 
-    // Truncate the vector if num_want is smaller than the vector length
-    if (num_want >= 0) && (num_want < swarm.len() as i32) {
-        swarm.truncate(num_want as usize);
+        packet.append(&mut wire().with_limit(2).serialize(&peer.port()).unwrap());
     }
 
-    for peer in swarm {
-        let (i, p): (String, i32) = peer;
-        let ip = IpAddr::from_str(&i).unwrap();
-
-        let mut ip_bytes = match ip {
-            IpAddr::V4(ip4) => {
-                let bytes = ip4.octets();
-                let mut it: Vec<u8> = Vec::new();
-                it.extend_from_slice(&bytes);
-                it
-            }
-            IpAddr::V6(ip6) => {
-                let double_bytes = ip6.segments();
-                options()
-                    .with_big_endian()
-                    .with_limit(16)
-                    .serialize(&double_bytes)
-                    .unwrap()
-            }
-        };
+    packet
+}
+
+// BEP 15 caps a scrape at about 74 info hashes per request
+pub const MAX_SCRAPE_HASHES: usize = 74;
+
+pub fn decode_client_scrape(packet: &[u8]) -> Result<Vec<[u8; 20]>, ParseError> {
+    debug!("Deserializing Client Scrape!");
+    debug!("packet len : {:?}", packet.len());
+    if packet.len() < 20 {
+        return Err(ParseError::NoInfoHash);
+    }
+
+    // Any trailing partial hash is ignored
+    Ok(packet
+        .chunks_exact(20)
+        .take(MAX_SCRAPE_HASHES)
+        .map(|chunk| {
+            let mut hash = [0u8; 20];
+            hash.copy_from_slice(chunk);
+            hash
+        })
+        .collect())
+}
+
+pub fn encode_server_scrape(transaction_id: i32, stats: Vec<ScrapeStats>) -> Vec<u8> {
+    let header = ScrapeResponse {
+        // Action for Scrape is always 2
+        action: 2,
+        transaction_id,
+    };
+
+    let mut packet = wire().serialize(&header).unwrap();
 
-        packet.append(&mut ip_bytes);
-        packet.append(&mut options().with_big_endian().with_limit(2).serialize(&(p as u16)).unwrap());
+    for stat in stats {
+        packet.append(&mut wire().with_limit(12).serialize(&stat).unwrap());
     }
 
     packet
 }
 
-pub fn encode_error(transaction_id: i32, error_string: &str) -> Vec<u8> {
+pub fn encode_error(transaction_id: i32, error: TrackerError) -> Vec<u8> {
     let err = ServerError {
         // Action (3 == Error)
         action: 3,
         transaction_id,
-        error: String::from_str(error_string).unwrap(),
     };
-    debug!("{:?}", err);
+    debug!("{:?}: {}", err, error.message());
+
+    let mut packet = wire().with_limit(8).serialize(&err).unwrap();
+    packet.extend_from_slice(error.message().as_bytes());
 
     // Return the packet
-    options().with_big_endian().serialize(error_string).unwrap()
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announce_interval_does_not_wrap() {
+        let packet = encode_server_announce(1, Vec::new(), u32::MAX, 0, 0);
+        assert_eq!(packet[8..12], i32::MAX.to_be_bytes());
+        let packet = encode_server_announce(1, Vec::new(), 1800, 0, 0);
+        assert_eq!(packet[8..12], 1800i32.to_be_bytes());
+    }
+
+    #[test]
+    fn scrape_hashes_are_capped() {
+        let body: Vec<u8> = (0..80).flat_map(|i| vec![i; 20]).collect();
+        let hashes = decode_client_scrape(&body).unwrap();
+        assert_eq!(hashes.len(), MAX_SCRAPE_HASHES);
+        assert_eq!(hashes[73], [73; 20]);
+
+        // A partial trailing hash is ignored, less than one hash is an error
+        assert_eq!(decode_client_scrape(&body[..50]).unwrap().len(), 2);
+        match decode_client_scrape(&body[..19]) {
+            Err(ParseError::NoInfoHash) => (),
+            x => panic!("expected NoInfoHash, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn truncated_packets_are_errors() {
+        match parse_header(&[0; 15]) {
+            Err(ParseError::TooShort { needed: 16, got: 15 }) => (),
+            x => panic!("expected a short header, got {:?}", x),
+        }
+        match decode_client_announce(&[0; 81]) {
+            Err(ParseError::TooShort { needed: 82, got: 81 }) => (),
+            x => panic!("expected a short announce, got {:?}", x),
+        }
+        assert!(decode_client_announce(&[0; 82]).is_ok());
+    }
 }
@@ -0,0 +1,375 @@
+//  rtracker: bittorrent tracker
+//  Copyright (C) 2019  Justin Noah <justinnoah@gmail.com>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License.
+//
+//  This program is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//   GNU Affero General Public License for more details.
+//
+//   You should have received a copy of the GNU Affero General Public License
+//   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Token buckets that keep the tracker from being used as a reflection amplifier
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+const SHARDS: usize = 16;
+// Buckets kept per shard, so a flood from spoofed sources can not grow the maps without bound.
+// Some 64 bytes each, 16 MB per limiter when every shard is full.
+const MAX_SHARD_BUCKETS: usize = 16384;
+
+/// Packets per second and how many may arrive at once, a rate of 0 is no limit
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Limit {
+    pub rate:  u32,
+    pub burst: u32,
+}
+
+impl Limit {
+    fn enabled(&self) -> bool {
+        self.rate > 0
+    }
+}
+
+// A Limit that can be swapped while packets are checked against it
+#[derive(Debug)]
+struct AtomicLimit(AtomicU64);
+
+impl AtomicLimit {
+    fn new(limit: Limit) -> AtomicLimit {
+        let a = AtomicLimit(AtomicU64::new(0));
+        a.store(limit);
+        a
+    }
+
+    fn load(&self) -> Limit {
+        let packed = self.0.load(Ordering::Relaxed);
+        Limit {
+            rate: (packed >> 32) as u32,
+            burst: packed as u32,
+        }
+    }
+
+    fn store(&self, limit: Limit) {
+        let packed = u64::from(limit.rate) << 32 | u64::from(limit.burst);
+        self.0.store(packed, Ordering::Relaxed);
+    }
+}
+
+/// What to do with a packet
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// Just went over a limit, answer with an error once
+    Reply,
+    /// Still over a limit, or the whole tracker is
+    Drop,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last:   Instant,
+    // Whether this client was already told it is over the limit, until the bucket is full again
+    warned: bool,
+}
+
+impl Bucket {
+    fn new(limit: Limit, now: Instant) -> Bucket {
+        Bucket {
+            tokens: f64::from(limit.burst),
+            last: now,
+            warned: false,
+        }
+    }
+
+    // Whether refilling would top the bucket up, without touching it
+    fn is_full(&self, limit: Limit, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens + elapsed * f64::from(limit.rate) >= f64::from(limit.burst)
+    }
+
+    fn refill(&mut self, limit: Limit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(limit.rate)).min(f64::from(limit.burst));
+        self.last = now;
+    }
+
+    /// Some(first time over) when there is no token to take. A client flooding at the rate
+    /// keeps its bucket near empty and is told once, not every time a token comes back.
+    fn take(&mut self, limit: Limit, now: Instant) -> Option<bool> {
+        self.refill(limit, now);
+        if self.tokens >= f64::from(limit.burst) {
+            self.warned = false;
+        }
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            let first = !self.warned;
+            self.warned = true;
+            Some(first)
+        }
+    }
+}
+
+// One bucket per key, split over a few locks
+struct Buckets {
+    limit:  AtomicLimit,
+    shards: Vec<Mutex<HashMap<IpAddr, Bucket>>>,
+}
+
+impl Buckets {
+    fn new(limit: Limit) -> Buckets {
+        Buckets {
+            limit: AtomicLimit::new(limit),
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    fn shard(&self, key: &IpAddr) -> &Mutex<HashMap<IpAddr, Bucket>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[(hasher.finish() % SHARDS as u64) as usize]
+    }
+
+    fn take(&self, key: IpAddr, now: Instant) -> Option<bool> {
+        let limit = self.limit.load();
+        if !limit.enabled() {
+            return None;
+        }
+        let mut shard = self.shard(&key).lock().unwrap_or_else(|e| e.into_inner());
+        if shard.len() >= MAX_SHARD_BUCKETS && !shard.contains_key(&key) {
+            evict(&mut shard, limit, now);
+        }
+        shard
+            .entry(key)
+            .or_insert_with(|| Bucket::new(limit, now))
+            .take(limit, now)
+    }
+
+    // A full bucket is the same as no bucket
+    fn prune(&self, now: Instant) -> usize {
+        let limit = self.limit.load();
+        let mut removed = 0;
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap_or_else(|e| e.into_inner());
+            let before = shard.len();
+            shard.retain(|_, b| {
+                b.refill(limit, now);
+                b.tokens < f64::from(limit.burst)
+            });
+            removed += before - shard.len();
+        }
+        removed
+    }
+}
+
+// Make room in a full shard. Full buckets go first, they are the same as no bucket. When that
+// is not enough, which takes a flood of many sources, the least recently seen go until a
+// quarter of the shard is free, so this only runs once every few thousand new sources.
+fn evict(shard: &mut HashMap<IpAddr, Bucket>, limit: Limit, now: Instant) {
+    shard.retain(|_, b| !b.is_full(limit, now));
+    let keep = MAX_SHARD_BUCKETS * 3 / 4;
+    if shard.len() <= keep {
+        return;
+    }
+    let mut seen: Vec<Instant> = shard.values().map(|b| b.last).collect();
+    let cutoff = *seen.select_nth_unstable(shard.len() - keep).1;
+    shard.retain(|_, b| b.last > cutoff);
+    debug!("Rate limiter full, kept the {} most recent sources of a shard", shard.len());
+}
+
+// The network a single user most likely controls all of
+fn prefix(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip4) => {
+            let o = ip4.octets();
+            IpAddr::V4(Ipv4Addr::new(o[0], o[1], o[2], 0))
+        }
+        IpAddr::V6(ip6) => {
+            let s = ip6.segments();
+            IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+        }
+    }
+}
+
+fn prefix_len(ip: IpAddr) -> u8 {
+    if ip.is_ipv4() {
+        24
+    } else {
+        64
+    }
+}
+
+pub struct RateLimiter {
+    ip:           Buckets,
+    prefix:       Buckets,
+    global_limit: AtomicLimit,
+    global:       Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(ip: Limit, prefix: Limit, global: Limit) -> RateLimiter {
+        RateLimiter {
+            ip: Buckets::new(ip),
+            prefix: Buckets::new(prefix),
+            global_limit: AtomicLimit::new(global),
+            global: Mutex::new(Bucket::new(global, Instant::now())),
+        }
+    }
+
+    /// Take a token for a packet from `ip`
+    pub fn check(&self, ip: IpAddr) -> Verdict {
+        let now = Instant::now();
+
+        // Over the global ceiling everyone is dropped, answering would only add to the load
+        let global_limit = self.global_limit.load();
+        if global_limit.enabled() {
+            let mut global = self.global.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(first) = global.take(global_limit, now) {
+                if first {
+                    warn!("Packet rate above {}/s, dropping", global_limit.rate);
+                }
+                return Verdict::Drop;
+            }
+        }
+
+        if let Some(first) = self.ip.take(ip, now) {
+            if first {
+                info!("Rate limiting {}", ip);
+                return Verdict::Reply;
+            }
+            return Verdict::Drop;
+        }
+
+        let net = prefix(ip);
+        if let Some(first) = self.prefix.take(net, now) {
+            if first {
+                info!("Rate limiting {}/{}", net, prefix_len(ip));
+                return Verdict::Reply;
+            }
+            return Verdict::Drop;
+        }
+
+        Verdict::Allow
+    }
+
+    /// Apply new limits, clients keep the tokens they have up to the new bursts
+    pub fn set_limits(&self, ip: Limit, prefix: Limit, global: Limit) {
+        self.ip.limit.store(ip);
+        self.prefix.limit.store(prefix);
+        self.global_limit.store(global);
+    }
+
+    /// Forget clients that have been quiet long enough to be back at a full bucket
+    pub fn prune(&self) -> usize {
+        let now = Instant::now();
+        self.ip.prune(now) + self.prefix.prune(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv6Addr};
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn spoofed(i: u32) -> IpAddr {
+        IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, (i >> 16) as u16, i as u16, 0, 0, 0, 1))
+    }
+
+    #[test]
+    fn spoofed_flood_is_bounded() {
+        let limit = Limit { rate: 1, burst: 1 };
+        let buckets = Buckets::new(limit);
+        let now = Instant::now();
+        // Every source empties its bucket, so none is full again
+        for i in 0..(SHARDS * MAX_SHARD_BUCKETS * 2) as u32 {
+            buckets.take(spoofed(i), now);
+        }
+        for shard in &buckets.shards {
+            let shard = shard.lock().unwrap();
+            assert!(shard.len() <= MAX_SHARD_BUCKETS);
+        }
+    }
+
+    #[test]
+    fn eviction_keeps_recent_sources() {
+        let limit = Limit { rate: 1, burst: 1 };
+        let mut shard = HashMap::new();
+        // Only ever added to, an Instant a minute before now may not exist on a fresh host
+        let long_ago = Instant::now();
+        let start = long_ago + Duration::from_secs(60);
+        for i in 0..MAX_SHARD_BUCKETS as u32 {
+            let seen = start + Duration::from_micros(u64::from(i));
+            let mut bucket = Bucket::new(limit, seen);
+            bucket.take(limit, seen);
+            shard.insert(spoofed(i), bucket);
+        }
+        // Full again by now, so these go first
+        let mut idle = Bucket::new(limit, long_ago);
+        idle.take(limit, long_ago);
+        shard.insert(spoofed(u32::MAX), idle);
+
+        let now = start + Duration::from_micros(MAX_SHARD_BUCKETS as u64);
+        evict(&mut shard, limit, now);
+        assert!(!shard.contains_key(&spoofed(u32::MAX)));
+        assert!(shard.len() <= MAX_SHARD_BUCKETS * 3 / 4);
+        assert!(shard.len() >= MAX_SHARD_BUCKETS * 3 / 4 - 1);
+        // The newest stay, and stay limited
+        let newest = spoofed(MAX_SHARD_BUCKETS as u32 - 1);
+        assert!(shard.contains_key(&newest));
+        assert!(!shard.contains_key(&spoofed(0)));
+    }
+
+    #[test]
+    fn limited_source_survives_a_flood() {
+        let limiter = RateLimiter::new(
+            Limit { rate: 1, burst: 2 },
+            Limit { rate: 0, burst: 0 },
+            Limit { rate: 0, burst: 0 },
+        );
+        let abuser = spoofed(u32::MAX);
+        assert_eq!(limiter.check(abuser), Verdict::Allow);
+        assert_eq!(limiter.check(abuser), Verdict::Allow);
+        assert_eq!(limiter.check(abuser), Verdict::Reply);
+        for i in 0..1000 {
+            limiter.check(spoofed(i));
+        }
+        assert_eq!(limiter.check(abuser), Verdict::Drop);
+    }
+
+    #[test]
+    fn flood_at_the_limit_gets_one_error() {
+        let limit = Limit { rate: 10, burst: 10 };
+        let buckets = Buckets::new(limit);
+        let source = spoofed(1);
+        let start = Instant::now();
+        let flood = |from: Duration| {
+            // Twice the rate for ten seconds, every freed token is taken at once
+            (0..200)
+                .filter(|&i| {
+                    let now = start + from + Duration::from_millis(i * 50);
+                    buckets.take(source, now) == Some(true)
+                })
+                .count()
+        };
+        assert_eq!(flood(Duration::from_secs(0)), 1);
+        // Not quiet long enough for a full bucket, still no second error
+        assert_eq!(flood(Duration::from_millis(10_500)), 0);
+        // A full bucket is a new client
+        assert_eq!(flood(Duration::from_secs(30)), 1);
+    }
+}
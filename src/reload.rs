@@ -0,0 +1,266 @@
+//  rtracker: bittorrent tracker
+//  Copyright (C) 2019  Justin Noah <justinnoah@gmail.com>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License.
+//
+//  This program is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//   GNU Affero General Public License for more details.
+//
+//   You should have received a copy of the GNU Affero General Public License
+//   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Applying a config file to a running tracker, at startup and again on SIGHUP or from the
+// admin API
+
+use std::path::Path;
+use std::sync::Arc;
+
+use access::{AccessList, ListSource};
+use blocklist::Blocklist;
+use config::{AccessMode, ServerConfig};
+use database::DbPool;
+use handler::{Settings, Tracker};
+use storage::PeerStore;
+use torrents::TorrentDir;
+
+// Whitelist whatever the .torrent files in the directory now describe
+fn scan_torrent_dir(dir: &mut TorrentDir, store: &dyn PeerStore, access: &AccessList) {
+    match dir.scan(store) {
+        Ok(true) => access.set_torrents(dir.hashes()),
+        Ok(false) => (),
+        Err(e) => warn!("Failed to read the torrent directory: {}", e),
+    }
+}
+
+/// Load the access list and blocklist `config` names, and rescan the torrent directory.
+/// Nothing is changed when a list can not be read.
+pub fn load_settings(
+    config: ServerConfig,
+    db_pool: Option<&DbPool>,
+    store: &dyn PeerStore,
+    torrent_dir: &mut Option<TorrentDir>,
+) -> Result<Settings, String> {
+    // Which torrents may be tracked, validate() made sure a list has somewhere to come from
+    let access = match (config.access_mode, config.access_file.clone(), db_pool) {
+        (AccessMode::Open, _, _) => AccessList::open(),
+        (mode, Some(path), _) => AccessList::new(mode, Some(ListSource::File(path))),
+        (mode, None, Some(pool)) => AccessList::new(mode, Some(ListSource::Table(pool.clone()))),
+        (mode, None, None) => AccessList::new(mode, None),
+    };
+    access
+        .reload()
+        .map_err(|e| format!("Failed to load the access list: {}", e))?;
+    let blocklist = Blocklist::load(&config.blocklist_files)
+        .map_err(|e| format!("Failed to load the blocklist: {}", e))?;
+
+    // The same directory keeps what it already read
+    if torrent_dir.as_ref().map(|d| d.path()) != config.torrent_dir.as_deref() {
+        *torrent_dir = config.torrent_dir.clone().map(TorrentDir::new);
+    }
+    if let Some(ref mut dir) = *torrent_dir {
+        if let Err(e) = dir.scan(store) {
+            warn!("Failed to read the torrent directory: {}", e);
+        }
+        access.set_torrents(dir.hashes());
+    }
+
+    Ok(Settings {
+        config,
+        access,
+        blocklist,
+    })
+}
+
+// Settings that only change with a restart keep their running values in `new`.
+// Returns the names of those that differed.
+fn keep_fixed(new: &mut ServerConfig, old: &ServerConfig) -> Vec<&'static str> {
+    let mut kept = Vec::new();
+    macro_rules! keep {
+        ($($field:ident),*) => {$(
+            if new.$field != old.$field {
+                kept.push(stringify!($field));
+                new.$field = old.$field.clone();
+            }
+        )*};
+    }
+    keep!(
+        address,
+        workers,
+        sockets,
+        batch_size,
+        http_address,
+        metrics_address,
+        admin_address,
+        admin_token,
+        db_path,
+        backend,
+        shards
+    );
+    kept
+}
+
+/// Read the config file again and apply it as a whole, or not at all when it is invalid.
+/// Returns the settings that changed but need a restart to take effect.
+pub fn reload(tracker: &Tracker) -> Result<Vec<&'static str>, String> {
+    let mut torrent_dir = tracker.torrent_dir.lock().unwrap_or_else(|e| e.into_inner());
+    let result = ServerConfig::new(&tracker.config_path)
+        .map_err(|e| e.to_string())
+        .and_then(|mut config| {
+            let kept = keep_fixed(&mut config, &tracker.settings().config);
+            // The file was validated on its own, what runs is the file with the running
+            // restart-only settings
+            config
+                .validate(Path::new(&tracker.config_path))
+                .map_err(|e| e.to_string())?;
+            let settings = load_settings(
+                config,
+                tracker.db_pool.as_ref(),
+                &*tracker.store,
+                &mut torrent_dir,
+            )?;
+            if let Some(ref pool) = tracker.db_pool {
+                resize_pool(pool, &settings.config)?;
+            }
+            Ok((settings, kept))
+        });
+    let (settings, kept) = match result {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Keeping the running config: {}", e);
+            return Err(e);
+        }
+    };
+
+    let config = &settings.config;
+    tracker
+        .limiter
+        .set_limits(config.ip_limit, config.prefix_limit, config.global_limit);
+    *tracker.settings.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(settings);
+
+    info!("Reloaded the config");
+    if !kept.is_empty() {
+        warn!("Restart to apply: {}", kept.join(", "));
+    }
+    Ok(kept)
+}
+
+// Last, a failure here leaves the running config and pool untouched
+fn resize_pool(pool: &DbPool, config: &ServerConfig) -> Result<(), String> {
+    if pool.size() == config.pool_size {
+        return Ok(());
+    }
+    pool.resize(config.pool_size)
+        .map_err(|e| format!("Failed to resize the connection pool: {}", e))?;
+    info!("Resized the connection pool to {}", config.pool_size);
+    if config.workers > config.pool_size {
+        warn!(
+            "{} workers share {} database connections, raise thread_pool_size to match",
+            config.workers, config.pool_size
+        );
+    }
+    Ok(())
+}
+
+/// Pick up changes to the access list file or table and the torrent directory
+pub fn refresh_access(tracker: &Tracker) {
+    let settings = tracker.settings();
+    if settings.access.mode() == AccessMode::Open {
+        return;
+    }
+    if let Err(e) = settings.access.refresh() {
+        warn!("Failed to reload the access list, keeping the old one: {}", e);
+    }
+    let mut torrent_dir = tracker.torrent_dir.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(ref mut dir) = *torrent_dir {
+        scan_torrent_dir(dir, &*tracker.store, &settings.access);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::RwLock;
+
+    use super::*;
+    use config::Backend;
+    use database::tests::announce;
+    use database::{db_connection_pool, SqliteStore};
+    use handler::tests::tracker;
+    use memory::MemoryStore;
+    use stats::Histogram;
+
+    // A tracker running `config`, reloading from a file holding `text`
+    fn running(name: &str, config: ServerConfig, text: &str) -> (Tracker, PathBuf) {
+        let path = env::temp_dir().join(format!("rtracker-reload-{}-{}.ini", name, process::id()));
+        fs::write(&path, text).unwrap();
+        let mut tracker = tracker(Arc::new(MemoryStore::new(4)));
+        tracker.config_path = path.to_str().unwrap().to_string();
+        let settings = Settings {
+            config,
+            access: AccessList::open(),
+            blocklist: Blocklist::default(),
+        };
+        tracker.settings = RwLock::new(Arc::new(settings));
+        (tracker, path)
+    }
+
+    #[test]
+    fn admin_token_stays_with_its_listener() {
+        let config = ServerConfig {
+            admin_address: Some(([127, 0, 0, 1], 9299).into()),
+            admin_token: Some(String::from("secret")),
+            ..ServerConfig::default()
+        };
+        let (tracker, path) = running("admin", config, "[tracker]\nmin_interval = 600\n");
+        let kept = reload(&tracker);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(kept.unwrap(), vec!["admin_address", "admin_token"]);
+        let settings = tracker.settings();
+        assert_eq!(settings.config.admin_token.as_deref(), Some("secret"));
+        assert_eq!(settings.config.min_interval, 600);
+    }
+
+    #[test]
+    fn merged_config_is_validated() {
+        let config = ServerConfig {
+            backend: Backend::Memory,
+            ..ServerConfig::default()
+        };
+        // Valid for the sqlite backend the file defaults to, not for the running memory one
+        let text = "[access]\nmode = whitelist\n";
+        let (tracker, path) = running("access", config, text);
+        let result = reload(&tracker);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+        assert_eq!(tracker.settings().config.access_mode, AccessMode::Open);
+    }
+
+    #[test]
+    fn pool_is_resized_keeping_the_database() {
+        // In memory, the database lives only as long as some connection to it is open
+        let pool = db_connection_pool(2, None, Arc::new(Histogram::default())).unwrap();
+        let store = SqliteStore::new(pool.clone()).unwrap();
+        store.announce(&announce(0xa5, "10.0.0.1:6881", 1, 0)).unwrap();
+        let config = ServerConfig {
+            pool_size: 2,
+            ..ServerConfig::default()
+        };
+        let (mut tracker, path) = running("pool", config, "[db]\nthread_pool_size = 5\n");
+        tracker.store = Arc::new(store);
+        tracker.db_pool = Some(pool.clone());
+
+        let kept = reload(&tracker);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(kept.unwrap(), Vec::<&str>::new());
+        assert_eq!(pool.size(), 5);
+        assert_eq!(tracker.settings().config.pool_size, 5);
+        assert_eq!(tracker.store.counts(&[0xa5; 20]).unwrap().seeders, 1);
+    }
+}
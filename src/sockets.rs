@@ -0,0 +1,49 @@
+//  rtracker: bittorrent tracker
+//  Copyright (C) 2019  Justin Noah <justinnoah@gmail.com>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License.
+//
+//  This program is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//   GNU Affero General Public License for more details.
+//
+//   You should have received a copy of the GNU Affero General Public License
+//   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+#[cfg(target_os = "linux")]
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Bind `count` UDP sockets to `address`.
+///
+/// On Linux more than one socket share the address through SO_REUSEPORT, and the kernel
+/// spreads clients over them by their address. Elsewhere there is only ever one socket.
+pub fn bind_udp(address: SocketAddr, count: usize) -> io::Result<Vec<UdpSocket>> {
+    if count <= 1 {
+        return Ok(vec![UdpSocket::bind(address)?]);
+    }
+    bind_reuseport(address, count)
+}
+
+#[cfg(target_os = "linux")]
+fn bind_reuseport(address: SocketAddr, count: usize) -> io::Result<Vec<UdpSocket>> {
+    (0..count)
+        .map(|_| {
+            let sock = Socket::new(Domain::for_address(address), Type::DGRAM, Some(Protocol::UDP))?;
+            sock.set_reuse_port(true)?;
+            sock.bind(&address.into())?;
+            Ok(sock.into())
+        })
+        .collect()
+}
+
+// main has warned that the sockets setting does not apply
+#[cfg(not(target_os = "linux"))]
+fn bind_reuseport(address: SocketAddr, _count: usize) -> io::Result<Vec<UdpSocket>> {
+    Ok(vec![UdpSocket::bind(address)?])
+}
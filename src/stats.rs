@@ -0,0 +1,108 @@
+//  rtracker: bittorrent tracker
+//  Copyright (C) 2019  Justin Noah <justinnoah@gmail.com>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License.
+//
+//  This program is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//   GNU Affero General Public License for more details.
+//
+//   You should have received a copy of the GNU Affero General Public License
+//   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Upper bounds of the latency buckets, in seconds
+pub const BUCKETS: [f64; 14] = [
+    0.000_01, 0.000_025, 0.000_05, 0.000_1, 0.000_25, 0.000_5, 0.001, 0.002_5, 0.005, 0.01,
+    0.025, 0.05, 0.1, 1.0,
+];
+
+/// Durations counted into fixed buckets, one more than BUCKETS for anything slower
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets:   [AtomicU64; BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let i = BUCKETS.iter().position(|&b| secs <= b).unwrap_or(BUCKETS.len());
+        incr(&self.buckets[i]);
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Append the histogram in Prometheus text format, `labels` being the ones it shares
+    pub fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut count = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let le = match BUCKETS.get(i) {
+                Some(b) => b.to_string(),
+                None => String::from("+Inf"),
+            };
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, sep, le, count);
+        }
+        let braces = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(out, "{}_sum{} {}", name, braces, sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braces, count);
+    }
+}
+
+/// Packets of one action
+#[derive(Debug, Default)]
+pub struct ActionStats {
+    pub received: AtomicU64,
+    /// Answered with a response of the action, not an error
+    pub answered: AtomicU64,
+    pub latency:  Histogram,
+}
+
+/// Counters shared by everything that touches a packet
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub packets_received: AtomicU64,
+    pub parse_errors:     AtomicU64,
+    pub error_responses:  AtomicU64,
+    pub dropped_packets:  AtomicU64,
+    /// Packets and connections from blocklisted addresses, never looked at
+    pub blocked:          AtomicU64,
+    /// Packets over a rate limit, answered once and then dropped
+    pub rate_limited:     AtomicU64,
+    pub connect:          ActionStats,
+    pub announce:         ActionStats,
+    pub scrape:           ActionStats,
+    /// Packets with an action the tracker does not know
+    pub unknown_action:   AtomicU64,
+    pub prunes:           AtomicU64,
+    pub pruned_peers:     AtomicU64,
+    /// How long the last prune took
+    pub prune_micros:     AtomicU64,
+    /// Time spent waiting for a database connection, shared with the pool
+    pub pool_wait:        Arc<Histogram>,
+}
+
+impl Stats {
+    pub fn action(&self, action: i32) -> Option<&ActionStats> {
+        match action {
+            0 => Some(&self.connect),
+            1 => Some(&self.announce),
+            2 => Some(&self.scrape),
+            _ => None,
+        }
+    }
+}
+
+pub fn incr(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
@@ -0,0 +1,168 @@
+//  rtracker: bittorrent tracker
+//  Copyright (C) 2019  Justin Noah <justinnoah@gmail.com>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License.
+//
+//  This program is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//   GNU Affero General Public License for more details.
+//
+//   You should have received a copy of the GNU Affero General Public License
+//   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use r2d2;
+use rusqlite;
+
+use packet_data_types::{Event, ScrapeStats};
+
+pub type InfoHash = [u8; 20];
+pub type PeerId = [u8; 20];
+
+/// What a client told us about itself on announce
+#[derive(Debug)]
+pub struct Announce {
+    pub info_hash: InfoHash,
+    pub peer_id:   PeerId,
+    pub addr:      SocketAddr,
+    pub remaining: i64,
+    pub event:     Event,
+}
+
+/// Peers of one family can only be handed to clients of the same family
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    pub fn of(ip: &IpAddr) -> Family {
+        match *ip {
+            IpAddr::V4(_) => Family::V4,
+            IpAddr::V6(_) => Family::V6,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Peer {
+    pub addr:      SocketAddr,
+    pub peer_id:   PeerId,
+    pub remaining: i64,
+}
+
+impl Peer {
+    pub fn is_seeder(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+/// Everything the store holds, summed over every torrent
+#[derive(Debug, Default)]
+pub struct Totals {
+    /// Torrents with at least one peer
+    pub torrents: i64,
+    pub seeders:  i64,
+    pub leechers: i64,
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Sqlite(rusqlite::Error),
+    Pool(r2d2::Error),
+    /// A database this version can not use
+    Schema(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StoreError::Sqlite(ref e) => write!(f, "sqlite: {}", e),
+            StoreError::Pool(ref e) => write!(f, "connection pool: {}", e),
+            StoreError::Schema(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> StoreError {
+        StoreError::Sqlite(e)
+    }
+}
+
+impl From<r2d2::Error> for StoreError {
+    fn from(e: r2d2::Error) -> StoreError {
+        StoreError::Pool(e)
+    }
+}
+
+/// Where the swarms live.
+///
+/// Implementations are shared between threads, so every method takes `&self`.
+pub trait PeerStore: Send + Sync {
+    /// Insert or refresh the announcing peer.
+    /// A completed event also counts towards the torrent's completed downloads.
+    fn announce(&self, announce: &Announce) -> Result<(), StoreError>;
+
+    /// Announce, then return the torrent's peers of `family` and its counts as they are after
+    /// the announce
+    fn announce_and_select(
+        &self,
+        announce: &Announce,
+        family: Family,
+    ) -> Result<(Vec<Peer>, ScrapeStats), StoreError> {
+        self.announce(announce)?;
+        let counts = self.counts(&announce.info_hash)?;
+        Ok((self.peers(&announce.info_hash, family)?, counts))
+    }
+
+    /// Every peer of a torrent with an address of the given family, seeders first
+    fn peers(&self, info_hash: &InfoHash, family: Family) -> Result<Vec<Peer>, StoreError>;
+
+    /// The seeders, completed downloads, and leechers of a torrent
+    fn counts(&self, info_hash: &InfoHash) -> Result<ScrapeStats, StoreError>;
+
+    /// Remove a single peer, returning whether it was there
+    fn remove(
+        &self,
+        info_hash: &InfoHash,
+        addr: SocketAddr,
+        peer_id: &PeerId,
+    ) -> Result<bool, StoreError>;
+
+    /// Remove the peer at `addr` whatever its peer_id, returning whether it was there
+    fn remove_peer(&self, info_hash: &InfoHash, addr: SocketAddr) -> Result<bool, StoreError>;
+
+    /// Forget a torrent's peers and completed count, returning how many peers it had
+    fn remove_torrent(&self, info_hash: &InfoHash) -> Result<usize, StoreError>;
+
+    /// Up to `limit` torrents with the most peers, most first
+    fn top(&self, limit: usize) -> Result<Vec<(InfoHash, ScrapeStats)>, StoreError>;
+
+    /// Remove every peer not heard from in `timeout` seconds, returning how many were removed
+    fn prune(&self, timeout: i64) -> Result<usize, StoreError>;
+
+    /// Remember the human readable name of a torrent
+    fn set_name(&self, info_hash: &InfoHash, name: &str) -> Result<(), StoreError>;
+
+    /// The name of a torrent, if one was ever set
+    fn name(&self, info_hash: &InfoHash) -> Result<Option<String>, StoreError>;
+
+    /// Torrent, seeder and leecher counts of the whole store
+    fn totals(&self) -> Result<Totals, StoreError>;
+
+    /// Make everything written so far durable before the process exits
+    fn flush(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    fn scrape(&self, hashes: &[InfoHash]) -> Result<Vec<ScrapeStats>, StoreError> {
+        hashes.iter().map(|h| self.counts(h)).collect()
+    }
+}
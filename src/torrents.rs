@@ -0,0 +1,279 @@
+//  rtracker: bittorrent tracker
+//  Copyright (C) 2019  Justin Noah <justinnoah@gmail.com>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License.
+//
+//  This program is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//   GNU Affero General Public License for more details.
+//
+//   You should have received a copy of the GNU Affero General Public License
+//   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// .torrent files, as far as a tracker cares about them: their info hashes and name
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::de::IgnoredAny;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use bencode::{self, Decoder};
+use storage::{InfoHash, PeerStore};
+
+#[derive(Debug)]
+pub enum TorrentError {
+    Bencode(bencode::Error),
+    /// Valid bencode, but not a torrent
+    Invalid(&'static str),
+}
+
+impl fmt::Display for TorrentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TorrentError::Bencode(ref e) => write!(f, "{}", e),
+            TorrentError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl From<bencode::Error> for TorrentError {
+    fn from(e: bencode::Error) -> TorrentError {
+        TorrentError::Bencode(e)
+    }
+}
+
+// What a tracker reads of the info dictionary, the rest is left undecoded
+#[derive(Deserialize)]
+struct Info {
+    name:         Vec<u8>,
+    /// v1 describes its pieces here, v2 in "file tree", hybrids in both
+    pieces:       Option<IgnoredAny>,
+    #[serde(rename = "meta version")]
+    meta_version: Option<i64>,
+}
+
+#[derive(Debug)]
+pub struct Torrent {
+    pub name: String,
+    /// SHA-1 of the info dictionary, for v1 and hybrid torrents
+    pub v1:   Option<InfoHash>,
+    /// SHA-256 of the info dictionary truncated to 20 bytes as clients announce it (BEP 52),
+    /// for v2 and hybrid torrents
+    pub v2:   Option<InfoHash>,
+}
+
+impl Torrent {
+    pub fn hashes(&self) -> Vec<InfoHash> {
+        self.v1.iter().chain(self.v2.iter()).cloned().collect()
+    }
+}
+
+/// Read the info hashes and name of a .torrent file's contents
+pub fn parse_torrent(contents: &[u8]) -> Result<Torrent, TorrentError> {
+    // The hashes are over the info dictionary exactly as it appears in the file
+    let raw_info = Decoder::new(contents)
+        .raw_dict_value(b"info")?
+        .ok_or(TorrentError::Invalid("no info dictionary"))?;
+    let info: Info = bencode::from_bytes(raw_info)?;
+    let name = String::from_utf8_lossy(&info.name).into_owned();
+
+    let v1 = if info.pieces.is_some() {
+        let mut hash = [0u8; 20];
+        hash.copy_from_slice(&Sha1::digest(raw_info));
+        Some(hash)
+    } else {
+        None
+    };
+    let v2 = if info.meta_version == Some(2) {
+        let mut hash = [0u8; 20];
+        hash.copy_from_slice(&Sha256::digest(raw_info)[..20]);
+        Some(hash)
+    } else {
+        None
+    };
+    if v1.is_none() && v2.is_none() {
+        return Err(TorrentError::Invalid("neither a v1 nor a v2 torrent"));
+    }
+
+    Ok(Torrent { name, v1, v2 })
+}
+
+/// A directory of .torrent files, rescanned for additions, changes and removals
+pub struct TorrentDir {
+    path:  PathBuf,
+    // The hashes each file was last read with, a file that could not be read has none
+    files: HashMap<PathBuf, (SystemTime, Vec<InfoHash>)>,
+}
+
+impl TorrentDir {
+    pub fn new(path: PathBuf) -> TorrentDir {
+        TorrentDir {
+            path,
+            files: HashMap::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read new and changed files, forget removed ones, and record the names of their
+    /// torrents in `store`. Returns whether the set of hashes may have changed.
+    pub fn scan(&mut self, store: &dyn PeerStore) -> io::Result<bool> {
+        let mut seen: HashSet<PathBuf> = HashSet::new();
+        let mut changed = false;
+
+        for entry in fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|e| e != "torrent") {
+                continue;
+            }
+            let modified = match fs::metadata(&path).and_then(|m| m.modified()) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("{}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            seen.insert(path.clone());
+            if self.files.get(&path).map(|f| f.0) == Some(modified) {
+                continue;
+            }
+
+            let hashes = load(&path, store);
+            self.files.insert(path, (modified, hashes));
+            changed = true;
+        }
+
+        let before = self.files.len();
+        self.files.retain(|path, _| seen.contains(path));
+        if self.files.len() != before {
+            changed = true;
+        }
+
+        Ok(changed)
+    }
+
+    /// Every hash of every torrent in the directory
+    pub fn hashes(&self) -> HashSet<InfoHash> {
+        self.files
+            .values()
+            .flat_map(|f| f.1.iter().cloned())
+            .collect()
+    }
+}
+
+fn load(path: &Path, store: &dyn PeerStore) -> Vec<InfoHash> {
+    let torrent = match fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|c| parse_torrent(&c).map_err(|e| e.to_string()))
+    {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Skipping {}: {}", path.display(), e);
+            return Vec::new();
+        }
+    };
+
+    let hashes = torrent.hashes();
+    for hash in &hashes {
+        if let Err(e) = store.set_name(hash, &torrent.name) {
+            warn!("Failed to store the name of {}: {}", path.display(), e);
+        }
+    }
+    info!("Allowing {:?} from {}", torrent.name, path.display());
+    hashes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use access::parse_hex_hash;
+    use memory::MemoryStore;
+
+    const V1: &[u8] = include_bytes!("../tests/fixtures/v1.torrent");
+    const V2: &[u8] = include_bytes!("../tests/fixtures/v2.torrent");
+    const HYBRID: &[u8] = include_bytes!("../tests/fixtures/hybrid.torrent");
+
+    // SHA-1 and truncated SHA-256 of each fixture's info dictionary, from Python's hashlib
+    const V1_HASH: &str = "8238f6572dfb2346b81f44f374e0e2b74b2d1e81";
+    const V2_HASH: &str = "fb060043e7f0f0a4c00231cf15617e8b861a66f8";
+    const HYBRID_V1_HASH: &str = "0107bb15edffcc6f5420af848bb29a3651224480";
+    const HYBRID_V2_HASH: &str = "2a082af1ede59ca914045d557925eec604b3ec69";
+
+    fn hash(hex: &str) -> InfoHash {
+        parse_hex_hash(hex).unwrap()
+    }
+
+    #[test]
+    fn v1_info_hash() {
+        let torrent = parse_torrent(V1).unwrap();
+        assert_eq!(torrent.name, "a.txt");
+        assert_eq!(torrent.v1, Some(hash(V1_HASH)));
+        assert_eq!(torrent.v2, None);
+    }
+
+    #[test]
+    fn v2_info_hash() {
+        let torrent = parse_torrent(V2).unwrap();
+        assert_eq!(torrent.v1, None);
+        assert_eq!(torrent.v2, Some(hash(V2_HASH)));
+    }
+
+    #[test]
+    fn hybrid_has_both_hashes() {
+        let torrent = parse_torrent(HYBRID).unwrap();
+        assert_eq!(torrent.v1, Some(hash(HYBRID_V1_HASH)));
+        assert_eq!(torrent.v2, Some(hash(HYBRID_V2_HASH)));
+        assert_eq!(torrent.hashes(), vec![hash(HYBRID_V1_HASH), hash(HYBRID_V2_HASH)]);
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_torrent() {
+        assert!(parse_torrent(b"d8:announce3:urle").is_err());
+        assert!(parse_torrent(b"d4:infoi1ee").is_err());
+        assert!(parse_torrent(b"d4:infod4:name1:aee").is_err());
+        assert!(parse_torrent(&V1[..V1.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn rescan_adds_and_removes() {
+        let path = env::temp_dir().join(format!("rtracker-torrents-{}", process::id()));
+        fs::create_dir_all(&path).unwrap();
+        let store = MemoryStore::new(4);
+        let mut dir = TorrentDir::new(path.clone());
+        fs::write(path.join("v1.torrent"), V1).unwrap();
+        fs::write(path.join("notes.txt"), V2).unwrap();
+
+        let first = dir.scan(&store).unwrap();
+        let only_v1 = dir.hashes();
+        fs::write(path.join("hybrid.torrent"), HYBRID).unwrap();
+        let second = dir.scan(&store).unwrap();
+        let both = dir.hashes();
+        let unchanged = dir.scan(&store).unwrap();
+        fs::remove_file(path.join("v1.torrent")).unwrap();
+        let third = dir.scan(&store).unwrap();
+        let only_hybrid = dir.hashes();
+        fs::remove_dir_all(&path).unwrap();
+
+        assert!(first && second && !unchanged && third);
+        assert_eq!(only_v1, [hash(V1_HASH)].iter().cloned().collect());
+        assert_eq!(both.len(), 3);
+        let hybrid: HashSet<InfoHash> =
+            [hash(HYBRID_V1_HASH), hash(HYBRID_V2_HASH)].iter().cloned().collect();
+        assert_eq!(only_hybrid, hybrid);
+        assert_eq!(store.name(&hash(HYBRID_V2_HASH)).unwrap().as_deref(), Some("a.txt"));
+    }
+}
@@ -0,0 +1,104 @@
+//  rtracker: bittorrent tracker
+//  Copyright (C) 2019  Justin Noah <justinnoah@gmail.com>
+//
+//  This program is free software: you can redistribute it and/or modify
+//  it under the terms of the GNU Affero General Public License as published by
+//  the Free Software Foundation, either version 3 of the License.
+//
+//  This program is distributed in the hope that it will be useful,
+//   but WITHOUT ANY WARRANTY; without even the implied warranty of
+//   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//   GNU Affero General Public License for more details.
+//
+//   You should have received a copy of the GNU Affero General Public License
+//   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Throughput of the tracker binary under the loadgen flood, one configuration against another.
+// Slow and only meaningful in release builds on an otherwise idle machine:
+//
+//   cargo test --release --test throughput -- --ignored --nocapture --test-threads 1
+
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+#[path = "../examples/load/mod.rs"]
+mod load;
+
+const SECONDS: u64 = 5;
+// What sockets = N must do better than sockets = 1 by, given cores to spread them over
+const SOCKETS_SPEEDUP: f64 = 1.2;
+
+fn cores() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+struct Tracker(Child);
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// A memory backend tracker without rate limits, `server` adds to its [server] section
+fn start(port: u16, server: &str) -> Tracker {
+    let config = format!(
+        "[server]\naddress = 127.0.0.1:{}\n{}\n[db]\nbackend = memory\n\
+         [ratelimit]\nip_rate = 0\nprefix_rate = 0\n",
+        port, server
+    );
+    let path = env::temp_dir().join(format!("rtracker-throughput-{}.ini", port));
+    fs::write(&path, config).unwrap();
+    let child = Command::new(env!("CARGO_BIN_EXE_rtracker"))
+        .arg("-c")
+        .arg(&path)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // Bound well before this, the first connects would otherwise be retried
+    thread::sleep(Duration::from_millis(500));
+    Tracker(child)
+}
+
+fn measure(port: u16, server: &str, clients: u32) -> f64 {
+    let _tracker = start(port, server);
+    let target: SocketAddr = ([127, 0, 0, 1], port).into();
+    let rate = load::run(target, clients, SECONDS, &mut |_| ());
+    println!("{:>40}: {:>9.0} announces/s", server.replace('\n', ", "), rate);
+    rate
+}
+
+#[test]
+#[ignore]
+fn sockets_scaling() {
+    // Half the cores answer, the other half generate the load
+    let workers = cores() / 2;
+    if workers < 2 {
+        println!("{} cores, sockets need at least 4 to be compared", cores());
+        return;
+    }
+    let clients = workers as u32;
+    let one = measure(7101, &format!("workers = {}\nsockets = 1", workers), clients);
+    let many = measure(7102, &format!("workers = {0}\nsockets = {0}", workers), clients);
+    println!("sockets = {}: {:.2}x sockets = 1 on {} cores", workers, many / one, cores());
+    assert!(
+        many >= one * SOCKETS_SPEEDUP,
+        "sockets = {} is not {}x sockets = 1",
+        workers,
+        SOCKETS_SPEEDUP
+    );
+}
+
+#[test]
+#[ignore]
+fn batch_size_scaling() {
+    let one = measure(7103, "workers = 1\nbatch_size = 1", 4);
+    let many = measure(7104, "workers = 1\nbatch_size = 32", 4);
+    assert!(one > 0.0 && many > 0.0);
+    println!("batch_size = 32: {:.2}x batch_size = 1", many / one);
+}